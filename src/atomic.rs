@@ -0,0 +1,204 @@
+//! Atomic replacement of files via temporary files in the same directory.
+
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::pathutil;
+
+const TEMP_SUFFIX: &str = ".syncd-tmp";
+
+/// How hard to try to get data onto the disk before a file is replaced
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Leave it to the OS
+    None,
+    /// Sync file data before the rename
+    #[default]
+    File,
+    /// Additionally sync the parent directory after the rename
+    Full,
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "file" => Ok(Self::File),
+            "full" => Ok(Self::Full),
+            _ => Err(format!(
+                "invalid durability '{}', expected none, file or full",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Self::None => "none",
+            Self::File => "file",
+            Self::Full => "full",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Returns a unique temporary path next to `path`.
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    if let Some(file_name) = pathutil::file_name(path) {
+        name.push(file_name);
+        name.push(".");
+    }
+    name.push(Uuid::new_v4().to_simple().to_string());
+    name.push(TEMP_SUFFIX);
+    path.with_file_name(name)
+}
+
+/// Returns true if the path was created by `temp_path`.
+#[cfg(unix)]
+pub fn is_temp_path(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    pathutil::file_name(path)
+        .map(|name| name.as_bytes().ends_with(TEMP_SUFFIX.as_bytes()))
+        .unwrap_or(false)
+}
+
+/// Moves a completely written temporary file to its final path.
+///
+/// `file` is the open temporary file which is synced according to `durability`.
+pub fn persist(
+    file: &File,
+    temp_path: &Path,
+    path: &Path,
+    durability: Durability,
+) -> io::Result<()> {
+    if durability != Durability::None {
+        file.sync_all()?;
+    }
+    std::fs::rename(temp_path, path)?;
+    if durability == Durability::Full {
+        if let Some(parent) = path.parent() {
+            File::open(parent)?.sync_all()?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes temporary files below `dir` which were not modified for at least `min_age`.
+///
+/// Leftovers of a crashed process are removed this way, while temporary files still written by a
+/// running process are kept. Symlinks are not followed. Entries which can't be read or removed
+/// are skipped with a warning. Returns the number of removed files.
+#[cfg(unix)]
+pub fn sweep(dir: &Path, min_age: Duration) -> usize {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!(path = %dir.display(), reason = %e, "failed to sweep directory");
+            return 0;
+        }
+    };
+    let mut removed = 0;
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!(path = %dir.display(), reason = %e, "failed to read directory entry");
+                continue;
+            }
+        };
+        let path = entry.path();
+        match sweep_entry(&entry, &path, min_age) {
+            Ok(n) => removed += n,
+            Err(e) => warn!(path = %path.display(), reason = %e, "failed to sweep"),
+        }
+    }
+    removed
+}
+
+#[cfg(unix)]
+fn sweep_entry(entry: &std::fs::DirEntry, path: &Path, min_age: Duration) -> io::Result<usize> {
+    let file_type = entry.file_type()?;
+    if file_type.is_dir() {
+        return Ok(sweep(path, min_age));
+    }
+    if file_type.is_file() && is_temp_path(path) {
+        let age = entry.metadata()?.modified()?.elapsed().unwrap_or_default();
+        if age >= min_age {
+            std::fs::remove_file(path)?;
+            return Ok(1);
+        }
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[cfg(unix)]
+    #[test]
+    fn temp_paths_are_hidden_siblings() {
+        let path = Path::new("dir/file.txt");
+        let temp = temp_path(path);
+        assert_eq!(temp.parent(), path.parent());
+        let name = temp.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with(".file.txt."));
+        assert!(is_temp_path(&temp));
+        assert!(!is_temp_path(path));
+        assert_ne!(temp, temp_path(path));
+    }
+
+    #[test]
+    fn persist_replaces_file() {
+        let dir = TempDir::new();
+        let path = dir.write("file", "old");
+        let temp = temp_path(&path);
+        std::fs::write(&temp, "new").unwrap();
+        let file = File::open(&temp).unwrap();
+        persist(&file, &temp, &path, Durability::Full).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        assert!(!temp.exists());
+    }
+
+    #[test]
+    fn durability_round_trips() {
+        for durability in [Durability::None, Durability::File, Durability::Full] {
+            assert_eq!(durability.to_string().parse(), Ok(durability));
+        }
+        assert!("always".parse::<Durability>().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sweep_removes_stale_temporary_files() {
+        let dir = TempDir::new();
+        let file = dir.write("sub/file", "");
+        let temp = temp_path(&file);
+        std::fs::write(&temp, "").unwrap();
+        let top_temp = temp_path(&dir.path().join("top"));
+        std::fs::write(&top_temp, "").unwrap();
+
+        assert_eq!(sweep(dir.path(), Duration::from_secs(60)), 0);
+        assert!(temp.exists());
+        assert_eq!(sweep(dir.path(), Duration::ZERO), 2);
+        assert!(!temp.exists());
+        assert!(!top_temp.exists());
+        assert!(file.exists());
+        assert_eq!(sweep(&dir.path().join("missing"), Duration::ZERO), 0);
+    }
+}
@@ -0,0 +1,94 @@
+//! Challenge-response authentication with a pre-shared key.
+//!
+//! The handler sends a random nonce, which the client answers with the HMAC-SHA256 of the nonce
+//! keyed with the shared secret.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+/// Environment variable the secret is read from, if no file is given
+pub const PSK_ENV: &str = "SYNCD_PSK";
+
+/// Reads the shared secret from `path` or from the `SYNCD_PSK` environment variable.
+///
+/// Trailing whitespace is ignored, so the secret file can end with a newline. Returns `None` if
+/// neither is set.
+pub fn read_secret(path: Option<&Path>) -> io::Result<Option<Vec<u8>>> {
+    let secret = match path {
+        Some(path) => fs::read(path)?,
+        None => match std::env::var_os(PSK_ENV) {
+            Some(secret) => secret.into_string().map(String::into_bytes).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is not valid unicode", PSK_ENV),
+                )
+            })?,
+            None => return Ok(None),
+        },
+    };
+    let len = secret
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(0, |pos| pos + 1);
+    if len == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "pre-shared key is empty",
+        ));
+    }
+    Ok(Some(secret[..len].to_vec()))
+}
+
+/// Returns a new random nonce.
+pub fn nonce() -> io::Result<[u8; 32]> {
+    let mut nonce = [0; 32];
+    getrandom::getrandom(&mut nonce).map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(nonce)
+}
+
+/// Computes the answer to a challenge.
+pub fn respond(secret: &[u8], nonce: &[u8; 32]) -> [u8; 32] {
+    mac(secret, nonce).finalize().into_bytes().into()
+}
+
+/// Verifies the answer to a challenge in constant time.
+pub fn verify(secret: &[u8], nonce: &[u8; 32], answer: &[u8; 32]) -> bool {
+    mac(secret, nonce).verify(answer).is_ok()
+}
+
+fn mac(secret: &[u8], nonce: &[u8; 32]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(nonce);
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn answers_are_verified() {
+        let nonce = nonce().unwrap();
+        assert_ne!(nonce, super::nonce().unwrap());
+        let answer = respond(b"secret", &nonce);
+        assert!(verify(b"secret", &nonce, &answer));
+        assert!(!verify(b"other", &nonce, &answer));
+        assert!(!verify(b"secret", &[0; 32], &answer));
+    }
+
+    #[test]
+    fn secret_files_are_trimmed() {
+        let dir = TempDir::new();
+        let path = dir.write("psk", "secret \n");
+        assert_eq!(read_secret(Some(&path)).unwrap(), Some(b"secret".to_vec()));
+
+        let path = dir.write("empty", "\n");
+        let e = read_secret(Some(&path)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
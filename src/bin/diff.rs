@@ -4,10 +4,10 @@ use std::path::PathBuf;
 
 use anyhow::Context as _;
 use argh::FromArgs;
-use fast_rsync::{apply_limited, diff, Signature, SignatureOptions};
+use fast_rsync::{apply_limited, diff, Signature};
 use memmap2::MmapOptions;
 use sha2::{Digest, Sha256};
-use syncd::init;
+use syncd::{init, signature};
 use tracing::info;
 
 /// Sync files from A to B over ssh in real time
@@ -19,6 +19,9 @@ struct Args {
     #[argh(positional)]
     /// file to update
     to: PathBuf,
+    /// block size of the signature [default: square root of the size of the file to update]
+    #[argh(option, from_str_fn(signature::parse_block_size))]
+    block_size: Option<u32>,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -27,12 +30,13 @@ fn main() -> anyhow::Result<()> {
     // signature of to
     let to_data = std::fs::read(&args.to)?;
 
+    let block_size = args
+        .block_size
+        .unwrap_or_else(|| signature::block_size(to_data.len() as u64));
     let mut storage = Vec::new();
-    let signature_options = SignatureOptions {
-        block_size: 4096,
-        crypto_hash_size: 8,
-    };
-    let sig = Signature::calculate(&to_data, &mut storage, signature_options);
+    let sig = Signature::calculate(&to_data, &mut storage, signature::options(block_size));
+    let mut serialized_sig = Vec::new();
+    sig.serialize(&mut serialized_sig);
     let indexed_sig = sig.index();
 
     // delta
@@ -47,6 +51,8 @@ fn main() -> anyhow::Result<()> {
 
     info!(bytes = from_mmap.len(), "from size");
     info!(bytes = to_data.len(), "to size");
+    info!(block_size, "block size");
+    info!(bytes = serialized_sig.len(), "signature size");
     info!(bytes = delta.len(), "delta size");
 
     // apply
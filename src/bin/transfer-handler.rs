@@ -2,24 +2,62 @@ use std::convert::Infallible;
 use std::fmt::Debug;
 use std::fs::File;
 use std::io::BufWriter;
+use std::os::unix::fs::{symlink, PermissionsExt};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use std::{fs, io};
 
 use anyhow::{anyhow, bail, Context as _};
 use argh::FromArgs;
-use fast_rsync::{apply_limited, Signature, SignatureOptions};
+use fast_rsync::{apply_limited, Signature};
+use filetime::FileTime;
 use futures_util::FutureExt;
+use syncd::atomic::{self, Durability};
+use syncd::auth;
+use syncd::compress;
+use syncd::confine::{confine, is_root};
+use syncd::pathutil::is_symlink;
 use syncd::proto::{
-    FileType, Transfer, TransferKind, TransferRequest, TransferResponse, TransferResponseKind,
+    Capabilities, FileType, Metadata, PathViolation, Transfer, TransferKind, TransferRequest,
+    TransferRequestKind, TransferResponse, TransferResponseKind,
 };
-use syncd::store::Store;
+use syncd::signature;
+use syncd::store::{self, Delta, Store};
+use syncd::tls;
 use syncd::write::WriterWithShasum;
-use syncd::{init, mmap, mmap_with_shasum, proto, transport, BoxAsynRead, BoxAsynWrite};
+use syncd::{
+    init, mmap, mmap_with_shasum, proto, shasum_bytes, transport, BoxAsynRead, BoxAsynWrite,
+};
 use tokio::net::TcpListener;
 use tokio::sync::Mutex;
+use tokio::time;
 use tokio_tower::pipeline;
-use tracing::{debug, info};
+use tracing::{debug, error, info, warn};
+
+#[cfg(test)]
+#[path = "../testutil.rs"]
+mod testutil;
+
+/// Maximum size of a signature chunk in a response
+const SIGNATURE_CHUNK_SIZE: usize = transport::MAX_CHUNK_SIZE;
+
+/// Maximum size of the entries of a listing page in a response
+const LISTING_CHUNK_SIZE: usize = transport::MAX_CHUNK_SIZE;
+
+/// Serialized size of a list entry beside its path, i.e. the path length and the file type
+const LIST_ENTRY_OVERHEAD: usize = 16;
+
+/// Time a client has to complete the TLS handshake and, separately, the protocol handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Capabilities supported by this handler
+const CAPABILITIES: Capabilities = Capabilities::SHA256
+    .union(Capabilities::SYMLINKS)
+    .union(Capabilities::METADATA)
+    .union(Capabilities::LIST)
+    .union(Capabilities::RESUME)
+    .union(Capabilities::ZSTD);
 
 /// Handler of transfer requests
 #[derive(Debug, FromArgs)]
@@ -30,43 +68,144 @@ struct Args {
     /// instead of communicating via stdin/stdout listen on a socket
     #[argh(option)]
     listen: Option<String>,
+    /// sync written files to disk before replacing: none, file or full [default: file]
+    #[argh(option, default = "Durability::default()")]
+    durability: Durability,
+    /// seconds without activity after which partial uploads are removed [default: 3600]
+    #[argh(option, default = "3600")]
+    expire: u64,
+    /// PEM certificate chain to accept TLS connections with (requires --listen)
+    #[argh(option)]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of the TLS certificate
+    #[argh(option)]
+    tls_key: Option<PathBuf>,
+    /// PEM CA certificates clients have to authenticate with (mutual TLS)
+    #[argh(option)]
+    tls_client_ca: Option<PathBuf>,
+    /// maximum length of a protocol frame in bytes, at least 1114112 [default: 8388608]
+    #[argh(
+        option,
+        default = "transport::DEFAULT_MAX_FRAME_LENGTH",
+        from_str_fn(transport::parse_max_frame_length)
+    )]
+    max_frame_length: usize,
+    /// file with the pre-shared key clients have to authenticate with [default: $SYNCD_PSK]
+    #[argh(option)]
+    psk_file: Option<PathBuf>,
+    /// bytes of deltas kept in memory before spilling them to disk [default: 67108864]
+    #[argh(option, default = "store::DEFAULT_DELTA_MEMORY_LIMIT")]
+    delta_memory_limit: usize,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args: Args = init();
 
+    let acceptor = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => {
+            if args.listen.is_none() {
+                bail!("TLS requires --listen");
+            }
+            let acceptor = tls::acceptor(cert, key, args.tls_client_ca.as_deref())
+                .context("failed to set up TLS")?;
+            Some(acceptor)
+        }
+        (None, None) if args.tls_client_ca.is_some() => {
+            bail!("--tls-client-ca requires --tls-cert and --tls-key")
+        }
+        (None, None) => None,
+        _ => bail!("--tls-cert and --tls-key have to be given together"),
+    };
+
+    let secret =
+        auth::read_secret(args.psk_file.as_deref()).context("failed to read pre-shared key")?;
+    if secret.is_none() && args.listen.is_some() {
+        warn!("no pre-shared key configured, accepting unauthenticated connections");
+    }
+
     info!("waiting for connection");
 
+    let expiry = Duration::from_secs(args.expire);
+    if args.root.is_dir() {
+        // leftovers of a previous crash; no session is active yet, so all of them are stale
+        let removed = atomic::sweep(&args.root, Duration::ZERO);
+        if removed > 0 {
+            info!(removed, "removed stale temporary files");
+        }
+    }
+
+    // partially received files are kept across connections to resume interrupted transfers
+    let store = Arc::new(Mutex::new(Store::new(args.delta_memory_limit)));
+    tokio::spawn(expire_periodically(store.clone(), expiry));
+
     loop {
         let (read, write): (BoxAsynRead, BoxAsynWrite) = if let Some(listen) = args.listen.as_ref()
         {
             let listener = TcpListener::bind(listen).await?;
-            let (socket, _addr) = listener.accept().await?;
-            let (read, write) = socket.into_split();
-            (Box::pin(read), (Box::pin(write)))
+            let (socket, addr) = listener.accept().await?;
+            info!(peer = %addr, "connection accepted");
+            if let Some(acceptor) = acceptor.as_ref() {
+                // only one connection is served at a time, so a silent client must not block
+                // the listener
+                let stream = match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        error!(reason = %e, "TLS handshake failed");
+                        continue;
+                    }
+                    Err(_) => {
+                        error!(peer = %addr, "TLS handshake timed out");
+                        continue;
+                    }
+                };
+                let (read, write) = tokio::io::split(stream);
+                (Box::pin(read), Box::pin(write))
+            } else {
+                let (read, write) = socket.into_split();
+                (Box::pin(read), (Box::pin(write)))
+            }
         } else {
+            info!("connection accepted");
             (Box::pin(tokio::io::stdin()), Box::pin(tokio::io::stdout()))
         };
 
-        info!("connection accepted");
+        let mut frames = transport::Frames::new(read, write, args.max_frame_length);
+        let session = time::timeout(HANDSHAKE_TIMEOUT, handshake(&mut frames, secret.as_deref()))
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("timed out")));
+        let session = match session {
+            Ok(session) => session,
+            Err(e) if args.listen.is_some() => {
+                error!(reason = %e, "handshake failed");
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        info!(
+            capabilities = ?session.capabilities,
+            compression_level = session.compression_level,
+            block_size = ?session.block_size,
+            "handshake done"
+        );
 
-        let transport = transport::BincodeTransport::<
-            proto::TransferRequest,
-            proto::TransferResponse,
-            _,
-            _,
-        >::new(read, write);
+        let transport = frames.into_transport::<proto::TransferRequest, proto::TransferResponse>();
 
         if !args.root.exists() {
             fs::create_dir_all(&args.root)?;
         } else if !args.root.is_dir() {
             bail!("{} exists and is not a directory", args.root.display());
         }
+        // paths of requests are confined to the canonical root
+        let root = args.root.canonicalize()?;
 
         let cx = TransferHandlerContext {
-            root: Arc::new(args.root.clone()),
-            store: Default::default(),
+            root: Arc::new(root),
+            store: store.clone(),
+            durability: args.durability,
+            block_size: session.block_size,
+            max_frame_length: args.max_frame_length,
+            compression: session.capabilities.contains(Capabilities::ZSTD),
         };
 
         let service = tower::service_fn(move |req| {
@@ -75,10 +214,22 @@ async fn main() -> anyhow::Result<()> {
 
         info!("running handler");
 
-        pipeline::Server::new(transport, service)
-            .await
-            .map_err(|e| anyhow!(e.to_string()))
-            .context("handle-transfer server failed")?;
+        let res = pipeline::Server::new(transport, service).await;
+
+        if args.listen.is_some() {
+            store.lock().await.release().await;
+        } else {
+            // no other connection will resume the partial files
+            store.lock().await.clear().await;
+        }
+
+        match res {
+            // a broken connection must not stop listening for the next one
+            Err(e) if args.listen.is_some() => error!(reason = %e, "connection failed"),
+            res => res
+                .map_err(|e| anyhow!(e.to_string()))
+                .context("handle-transfer server failed")?,
+        }
 
         if args.listen.is_none() {
             break;
@@ -90,22 +241,145 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Removes entries of the store which were inactive for longer than `expiry`.
+async fn expire_periodically(store: Arc<Mutex<Store>>, expiry: Duration) {
+    let period = expiry.clamp(Duration::from_secs(1), Duration::from_secs(60));
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let expired = store.lock().await.expire(expiry).await;
+        if expired > 0 {
+            info!(expired, "removed abandoned partial uploads");
+        }
+    }
+}
+
+/// Settings negotiated with the client
+#[derive(Debug, Clone, Copy)]
+struct Session {
+    capabilities: Capabilities,
+    compression_level: i32,
+    /// block size of signatures requested by the client
+    block_size: Option<u32>,
+}
+
+/// Answers the handshake of the client.
+///
+/// Returns the settings of this session. On version mismatch the client is notified before the
+/// error is returned.
+///
+/// If a pre-shared key is given, the client has to answer a challenge before the session starts.
+async fn handshake(
+    frames: &mut transport::Frames<BoxAsynRead, BoxAsynWrite>,
+    secret: Option<&[u8]>,
+) -> anyhow::Result<Session> {
+    let hello: proto::Hello = frames.recv().await.context("failed to receive handshake")?;
+    debug!(?hello, "handshake");
+
+    let capabilities = hello.capabilities.intersection(CAPABILITIES);
+    let rejection = if hello.version != proto::PROTOCOL_VERSION {
+        Some(format!(
+            "protocol version mismatch: transfer speaks {}, transfer-handler speaks {}",
+            hello.version,
+            proto::PROTOCOL_VERSION
+        ))
+    } else if !capabilities.contains(Capabilities::SHA256) {
+        Some("no common hash algorithm".to_string())
+    } else if hello.block_size == Some(0) {
+        Some("invalid block size 0".to_string())
+    } else {
+        None
+    };
+
+    if let Some(reason) = rejection {
+        let welcome = proto::Welcome::Rejected {
+            version: proto::PROTOCOL_VERSION,
+            reason: reason.clone(),
+        };
+        frames.send(welcome).await?;
+        bail!(reason);
+    }
+
+    let compression_level = compress::clamp_level(hello.compression_level);
+    let nonce = secret.map(|_| auth::nonce()).transpose()?;
+    let welcome = proto::Welcome::Accepted {
+        version: proto::PROTOCOL_VERSION,
+        capabilities,
+        compression_level,
+        challenge: nonce,
+    };
+    frames.send(welcome).await?;
+
+    if let (Some(secret), Some(nonce)) = (secret, nonce) {
+        let authenticate: proto::Authenticate = frames
+            .recv()
+            .await
+            .context("failed to receive authentication")?;
+        if !auth::verify(secret, &nonce, &authenticate.answer) {
+            frames.send(proto::AuthResult::Rejected).await?;
+            bail!("authentication failed");
+        }
+        frames.send(proto::AuthResult::Accepted).await?;
+    }
+
+    Ok(Session {
+        capabilities,
+        compression_level,
+        block_size: hello.block_size,
+    })
+}
+
 #[derive(Debug, Clone, Default)]
 struct TransferHandlerContext {
     root: Arc<PathBuf>,
     store: Arc<Mutex<Store>>,
+    durability: Durability,
+    /// block size of signatures instead of choosing it from the file size
+    block_size: Option<u32>,
+    /// limit of decompressed chunks, which are not larger than a frame when sent raw
+    max_frame_length: usize,
+    /// zstd compression was negotiated
+    compression: bool,
 }
 
-async fn transfer_handler(cx: TransferHandlerContext, req: TransferRequest) -> TransferResponse {
+async fn transfer_handler(
+    cx: TransferHandlerContext,
+    mut req: TransferRequest,
+) -> TransferResponse {
     debug!(request = ?req, "incoming");
 
     let id = req.id;
+    if let Err((path, violation)) = validate_paths(&cx.root, &req) {
+        warn!(path = %path.display(), %violation, "refusing path");
+        return TransferResponse {
+            id,
+            kind: TransferResponseKind::InvalidPath { path, violation },
+        };
+    }
+    if let Some(transfer) = req.transfer.as_mut() {
+        if transfer.compressed && !cx.compression {
+            return TransferResponse {
+                id,
+                kind: TransferResponseKind::CantHandle {
+                    reason: "compressed data without negotiated compression".to_string(),
+                },
+            };
+        }
+        if let Err(e) = decompress_transfer(transfer, cx.max_frame_length) {
+            return TransferResponse { id, kind: e.into() };
+        }
+    }
+
     let resp = match req.kind {
-        proto::TransferRequestKind::Check => handle_check(cx, req),
+        proto::TransferRequestKind::Check => handle_check(cx, req).await,
         proto::TransferRequestKind::Delta => handle_delta(cx, req).await,
         proto::TransferRequestKind::Contents => handle_contents(cx, req).await,
-        proto::TransferRequestKind::Remove => handle_remove(&cx.root, req),
+        proto::TransferRequestKind::Resume => handle_resume(cx, req).await,
+        proto::TransferRequestKind::Signature { .. } => handle_signature(cx, req).await,
+        proto::TransferRequestKind::Remove { .. } => handle_remove(&cx.root, req),
         proto::TransferRequestKind::Rename { .. } => handle_rename(&cx.root, req),
+        proto::TransferRequestKind::Metadata => handle_metadata(&cx.root, req),
+        proto::TransferRequestKind::List { .. } => handle_list(&cx.root, req),
     };
 
     let resp = resp.unwrap_or_else(|e| TransferResponse {
@@ -119,7 +393,57 @@ async fn transfer_handler(cx: TransferHandlerContext, req: TransferRequest) -> T
     resp
 }
 
-fn handle_check(
+/// Replaces compressed data of a transfer by the decompressed data.
+///
+/// The data decompresses to at most `max_size` bytes, or `data_size` if it is smaller.
+fn decompress_transfer(transfer: &mut Transfer, max_size: usize) -> io::Result<()> {
+    if !transfer.compressed {
+        return Ok(());
+    }
+    let max_size = transfer
+        .data_size
+        .map_or(max_size, |size| size.min(max_size));
+    transfer.data = compress::decompress(&transfer.data, max_size)?;
+    transfer.compressed = false;
+    Ok(())
+}
+
+/// Checks that all paths of the request are confined to the root.
+fn validate_paths(root: &Path, req: &TransferRequest) -> Result<(), (PathBuf, PathViolation)> {
+    // metadata is written through a symlink at the path itself, and listings and signatures are
+    // read through it; all other requests replace the path, so a symlink there is not followed
+    let follow_last = match req.kind {
+        TransferRequestKind::Metadata
+        | TransferRequestKind::Signature { .. }
+        | TransferRequestKind::List { .. } => true,
+        TransferRequestKind::Check
+        | TransferRequestKind::Contents
+        | TransferRequestKind::Resume
+        | TransferRequestKind::Delta
+        | TransferRequestKind::Remove { .. }
+        | TransferRequestKind::Rename { .. } => false,
+    };
+    confine(root, &req.path, follow_last).map_err(|violation| (req.path.clone(), violation))?;
+
+    match &req.kind {
+        TransferRequestKind::Remove { .. } | TransferRequestKind::Rename { .. }
+            if is_root(&req.path) =>
+        {
+            return Err((req.path.clone(), PathViolation::Root));
+        }
+        _ => (),
+    }
+
+    if let TransferRequestKind::Rename { new_path } = &req.kind {
+        if is_root(new_path) {
+            return Err((new_path.clone(), PathViolation::Root));
+        }
+        confine(root, new_path, false).map_err(|violation| (new_path.clone(), violation))?;
+    }
+    Ok(())
+}
+
+async fn handle_check(
     cx: TransferHandlerContext,
     req: TransferRequest,
 ) -> anyhow::Result<TransferResponse> {
@@ -133,50 +457,115 @@ fn handle_check(
             let transfer = req
                 .transfer
                 .ok_or_else(|| anyhow!("missing transfer data on check file request"))?;
-            let kind = handle_check_file(&path, transfer).unwrap_or_else(From::from);
+            let kind = match handle_check_file(&path, transfer, req.metadata, cx.block_size) {
+                Ok(TransferResponseKind::Different {
+                    signature,
+                    block_size,
+                    signature_size,
+                }) if signature.len() > SIGNATURE_CHUNK_SIZE => {
+                    // the rest of the signature is fetched with signature requests
+                    let first_chunk = signature[..SIGNATURE_CHUNK_SIZE].to_vec();
+                    cx.store.lock().await.insert_signature(path, signature);
+                    TransferResponseKind::Different {
+                        signature: first_chunk,
+                        block_size,
+                        signature_size,
+                    }
+                }
+                kind => kind.unwrap_or_else(From::from),
+            };
             Ok(TransferResponse { id: req.id, kind })
         }
         FileType::Symlink => {
-            bail!("symlinks are not implemented");
+            let target = req
+                .link_target
+                .ok_or_else(|| anyhow!("missing link target on check symlink request"))?;
+            let kind = handle_check_symlink(&path, &target).unwrap_or_else(From::from);
+            Ok(TransferResponse { id: req.id, kind })
         }
     }
 }
 
 fn handle_check_dir(path: &Path) -> io::Result<TransferResponseKind> {
-    if path.exists() {
-        if !path.is_dir() {
-            fs::remove_file(&path)?;
+    // Note: symlink_metadata does not follow symlinks, so a symlink to a directory is replaced.
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => (),
+        Ok(_) => {
+            fs::remove_file(path)?;
             fs::create_dir_all(path)?;
         }
-    } else {
-        fs::create_dir_all(path)?;
+        Err(e) if e.kind() == io::ErrorKind::NotFound => fs::create_dir_all(path)?,
+        Err(e) => return Err(e),
+    }
+    Ok(TransferResponseKind::Ok)
+}
+
+fn handle_check_symlink(path: &Path, target: &Path) -> io::Result<TransferResponseKind> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => {
+            if fs::read_link(path)? == target {
+                return Ok(TransferResponseKind::Ok);
+            }
+            fs::remove_file(path)?;
+        }
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path)?,
+        Ok(_) => fs::remove_file(path)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
     }
+    symlink(target, path)?;
     Ok(TransferResponseKind::Ok)
 }
 
-fn handle_check_file(path: &Path, transfer: Transfer) -> io::Result<TransferResponseKind> {
+fn handle_check_file(
+    path: &Path,
+    transfer: Transfer,
+    metadata: Option<Metadata>,
+    block_size: Option<u32>,
+) -> io::Result<TransferResponseKind> {
     debug!(
         "handle_check_file at {} with transfer {:?}",
         path.display(),
         transfer
     );
-    if !path.exists() {
+    if is_symlink(path) {
+        // never write through a symlink
+        fs::remove_file(path)?;
+        Ok(TransferResponseKind::NeedContents)
+    } else if !path.exists() {
         Ok(TransferResponseKind::NeedContents)
+    } else if fs::metadata(path)?.len() == 0 {
+        // empty files can't be memory mapped, and there is nothing to compute a delta against
+        if transfer.shasum != shasum_bytes([]) {
+            return Ok(TransferResponseKind::NeedContents);
+        }
+        if let Some(metadata) = metadata {
+            apply_metadata(path, &metadata)?;
+        }
+        Ok(TransferResponseKind::Ok)
     } else {
         let (mmap, shasum) = mmap_with_shasum(path)?;
 
         if shasum == transfer.shasum {
+            if let Some(metadata) = metadata {
+                apply_metadata(path, &metadata)?;
+            }
             Ok(TransferResponseKind::Ok)
+        } else if transfer.shasum == shasum_bytes([]) {
+            // an empty file has nothing to compute a delta from
+            Ok(TransferResponseKind::NeedContents)
         } else {
             // TODO: Reuse buffers
             let mut storage = Vec::new();
             let mut signature = Vec::new();
-            let signature_options = SignatureOptions {
-                block_size: 4096,
-                crypto_hash_size: 8,
-            };
-            Signature::calculate(&mmap, &mut storage, signature_options).serialize(&mut signature);
-            Ok(TransferResponseKind::Different { signature })
+            let block_size = block_size.unwrap_or_else(|| signature::block_size(mmap.len() as u64));
+            Signature::calculate(&mmap, &mut storage, signature::options(block_size))
+                .serialize(&mut signature);
+            Ok(TransferResponseKind::Different {
+                signature_size: signature.len() as u64,
+                signature,
+                block_size,
+            })
         }
     }
 }
@@ -191,24 +580,41 @@ async fn handle_contents(
     let transfer = req
         .transfer
         .ok_or_else(|| anyhow!("transfer data missing for contents request"))?;
-    if transfer.kind != TransferKind::Contents {
-        bail!("transfer kind is not contents for contents request");
+    let path = cx.root.join(req.path);
+    match transfer.kind {
+        TransferKind::Contents => (),
+        TransferKind::Empty => {
+            debug!(path = %path.display(), "create empty file");
+            create_empty_file(&path, cx.durability)?;
+            if let Some(metadata) = req.metadata {
+                apply_metadata(&path, &metadata)?;
+            }
+            return Ok(TransferResponse {
+                id: req.id,
+                kind: TransferResponseKind::Ok,
+            });
+        }
+        _ => bail!("transfer kind is not contents for contents request"),
     }
     let file_size = transfer
         .file_size
         .ok_or_else(|| anyhow!("contents transfer does not have file_size"))?;
 
-    let path = cx.root.join(req.path);
     debug!(path = %path.display(), file_size, "handle_contents");
 
     let mut store = cx.store.lock().await;
     let total_bytes = store
-        .push_file_chunk(path.clone(), transfer.shasum, &transfer.data)
+        .push_file_chunk(
+            path.clone(),
+            transfer.shasum,
+            transfer.offset,
+            &transfer.data,
+        )
         .await?;
     if total_bytes == file_size as u64 {
         // we got the last chunk
         let shasum = store
-            .remove_file(path)
+            .finish_file(&path, cx.durability)
             .await?
             .expect("logic error: file not in store");
         if shasum != transfer.shasum {
@@ -218,6 +624,9 @@ async fn handle_contents(
                 hex::encode(transfer.shasum)
             );
         }
+        if let Some(metadata) = req.metadata {
+            apply_metadata(&path, &metadata)?;
+        }
     }
 
     Ok(TransferResponse {
@@ -226,6 +635,61 @@ async fn handle_contents(
     })
 }
 
+/// Creates an empty file at `path` or truncates an existing one.
+fn create_empty_file(path: &Path, durability: Durability) -> io::Result<()> {
+    // the file is replaced instead of truncated in place, so a symlink at the path is not followed
+    let temp_path = atomic::temp_path(path);
+    let f = File::create(&temp_path)?;
+    if let Err(e) = atomic::persist(&f, &temp_path, path, durability) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    Ok(())
+}
+
+async fn handle_resume(
+    cx: TransferHandlerContext,
+    req: TransferRequest,
+) -> anyhow::Result<TransferResponse> {
+    let transfer = req
+        .transfer
+        .ok_or_else(|| anyhow!("transfer data missing for resume request"))?;
+
+    let path = cx.root.join(req.path);
+    let offset = cx.store.lock().await.file_offset(&path, transfer.shasum);
+    if offset != 0 {
+        info!(path = %path.display(), offset, "resuming transfer");
+    }
+
+    Ok(TransferResponse {
+        id: req.id,
+        kind: TransferResponseKind::ResumeAt { offset },
+    })
+}
+
+async fn handle_signature(
+    cx: TransferHandlerContext,
+    req: TransferRequest,
+) -> anyhow::Result<TransferResponse> {
+    let offset = match req.kind {
+        TransferRequestKind::Signature { offset } => offset,
+        _ => bail!("unexpected request kind in signature"),
+    };
+
+    let path = cx.root.join(req.path);
+    let data = cx
+        .store
+        .lock()
+        .await
+        .signature_chunk(&path, offset, SIGNATURE_CHUNK_SIZE)
+        .ok_or_else(|| anyhow!("no pending signature for {}", path.display()))?;
+
+    Ok(TransferResponse {
+        id: req.id,
+        kind: TransferResponseKind::SignatureChunk { data },
+    })
+}
+
 async fn handle_delta(
     cx: TransferHandlerContext,
     req: TransferRequest,
@@ -242,17 +706,19 @@ async fn handle_delta(
     let file_size = transfer
         .file_size
         .ok_or_else(|| anyhow!("delta transfer does not have file_size"))?;
-    let data_size = transfer
-        .data_size
-        .ok_or_else(|| anyhow!("delta transfer does not have data_size"))?;
 
-    let path = cx.root.join(req.path);
+    let path = cx.root.join(&req.path);
 
-    // TODO: optimize the case where the is only a single chunk
     let mut store = cx.store.lock().await;
-    let delta = store.push_delta_chunk(path.clone(), transfer.shasum, &transfer.data);
-
-    if delta.len() != data_size {
+    store
+        .push_delta_chunk(
+            path.clone(),
+            transfer.shasum,
+            transfer.offset,
+            &transfer.data,
+        )
+        .await?;
+    if !transfer.last {
         // need more delta chunks
         return Ok(TransferResponse {
             id: req.id,
@@ -261,41 +727,110 @@ async fn handle_delta(
     }
 
     // we got the last delta chunk
-    store.remove_delta(&path);
+    let delta = store
+        .finish_delta(&path)
+        .await?
+        .ok_or_else(|| anyhow!("delta vanished from store"))?;
     drop(store);
 
+    // the base is read through a symlink at the path, unlike the file replacing it
+    if let Err(violation) = confine(&cx.root, &req.path, true) {
+        warn!(path = %path.display(), %violation, "refusing delta base");
+        if let Delta::File(delta_path) = delta {
+            let _ = fs::remove_file(delta_path);
+        }
+        return Ok(TransferResponse {
+            id: req.id,
+            kind: TransferResponseKind::InvalidPath {
+                path: req.path,
+                violation,
+            },
+        });
+    }
     let mmap = mmap(&path)?;
-    fs::remove_file(&path)?; // unlink previous file to avoid overriding the mmap
 
-    let f = File::create(&path)?;
-    let mut out = WriterWithShasum::new(BufWriter::new(f));
-    apply_limited(&mmap, &transfer.data, &mut out, file_size)?;
-    let shasum = out.finalize();
+    // the previous file stays in place until the new one is complete
+    let temp_path = atomic::temp_path(&path);
+    let res = match &delta {
+        Delta::Memory(delta) => apply_delta(&mmap, delta, file_size, &temp_path),
+        Delta::File(delta_path) => {
+            let res = syncd::mmap(delta_path)
+                .map_err(anyhow::Error::from)
+                .and_then(|delta| apply_delta(&mmap, &delta, file_size, &temp_path));
+            let _ = fs::remove_file(delta_path);
+            res
+        }
+    };
+    let f = match res {
+        Ok((f, shasum)) if shasum == transfer.shasum => f,
+        Ok(_) => {
+            // apply failed => ask for the full contents
+            fs::remove_file(&temp_path)?;
+            return Ok(TransferResponse {
+                id: req.id,
+                kind: TransferResponseKind::NeedContents,
+            });
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e);
+        }
+    };
+    if let Err(e) = atomic::persist(&f, &temp_path, &path, cx.durability) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e.into());
+    }
 
-    if shasum == transfer.shasum {
-        // apply worked
-        Ok(TransferResponse {
-            id: req.id,
-            kind: TransferResponseKind::Ok,
-        })
-    } else {
-        // apply failed => ask for the full contents
-        Ok(TransferResponse {
-            id: req.id,
-            kind: TransferResponseKind::NeedContents,
-        })
+    if let Some(metadata) = req.metadata {
+        apply_metadata(&path, &metadata)?;
     }
+    Ok(TransferResponse {
+        id: req.id,
+        kind: TransferResponseKind::Ok,
+    })
 }
 
-fn handle_remove(root: &Path, req: TransferRequest) -> anyhow::Result<TransferResponse> {
-    // Assumption: if we remove a dir, then all files were removed before by other requests.
-    // This is not true, if requests are multiplexed, which is not the case atm.
+/// Applies the delta to the base file and writes the result to a new file at `temp_path`.
+///
+/// Returns the written file together with the sha256 sum of its data.
+fn apply_delta(
+    base: &[u8],
+    delta: &[u8],
+    file_size: usize,
+    temp_path: &Path,
+) -> anyhow::Result<(File, [u8; 32])> {
+    let f = File::create(temp_path)?;
+    let mut out = WriterWithShasum::new(BufWriter::new(f));
+    apply_limited(base, delta, &mut out, file_size)?;
+    let (writer, shasum) = out.into_inner();
+    let f = writer.into_inner().map_err(|e| e.into_error())?;
+    Ok((f, shasum))
+}
 
+/// Removes the entry at the path according to its actual type.
+///
+/// Removing a missing entry succeeds, since it might have been removed together with its parent
+/// before. A symlink is removed itself, also by a recursive removal, so nothing outside of the
+/// root is touched.
+fn handle_remove(root: &Path, req: TransferRequest) -> anyhow::Result<TransferResponse> {
+    let recursive = match req.kind {
+        TransferRequestKind::Remove { recursive } => recursive,
+        _ => bail!("unexpected request kind in remove"),
+    };
     let path = root.join(req.path);
 
-    match req.file_type {
-        FileType::Dir => fs::remove_dir(path)?,
-        FileType::File | FileType::Symlink => fs::remove_file(path)?,
+    let res = match fs::symlink_metadata(&path) {
+        Ok(metadata) if metadata.is_dir() && recursive => fs::remove_dir_all(&path),
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir(&path),
+        Ok(_) => fs::remove_file(&path),
+        Err(e) => Err(e),
+    };
+    match res {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            debug!(path = %path.display(), "already removed");
+        }
+        Err(e) => return Err(e.into()),
     }
 
     Ok(TransferResponse {
@@ -304,6 +839,10 @@ fn handle_remove(root: &Path, req: TransferRequest) -> anyhow::Result<TransferRe
     })
 }
 
+/// Moves a file, symlink or directory to the new path, replacing whatever is there.
+///
+/// Missing parent directories of the new path are created. If the source is missing, e.g. because
+/// its transfer failed before, the client is asked for the contents instead.
 fn handle_rename(root: &Path, req: TransferRequest) -> anyhow::Result<TransferResponse> {
     let from = root.join(req.path);
     let to = match req.kind {
@@ -311,13 +850,32 @@ fn handle_rename(root: &Path, req: TransferRequest) -> anyhow::Result<TransferRe
         _ => bail!("unexpected request kind in rename"),
     };
 
-    if to.exists() {
-        if to.is_dir() {
-            fs::remove_dir_all(&to)?;
-        } else {
-            fs::remove_file(&to)?;
+    let from_metadata = match fs::symlink_metadata(&from) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            debug!(path = %from.display(), "rename source missing");
+            return Ok(TransferResponse {
+                id: req.id,
+                kind: TransferResponseKind::NeedContents,
+            });
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    if from != to {
+        match fs::symlink_metadata(&to) {
+            // a file replaces another one atomically
+            Ok(metadata) if !metadata.is_dir() && !from_metadata.is_dir() => (),
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&to)?,
+            Ok(_) => fs::remove_file(&to)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                if let Some(parent) = to.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+            }
+            Err(e) => return Err(e.into()),
         }
-        fs::rename(from, to)?;
+        fs::rename(&from, &to)?;
     }
 
     Ok(TransferResponse {
@@ -325,3 +883,435 @@ fn handle_rename(root: &Path, req: TransferRequest) -> anyhow::Result<TransferRe
         kind: TransferResponseKind::Ok,
     })
 }
+
+fn handle_metadata(root: &Path, req: TransferRequest) -> anyhow::Result<TransferResponse> {
+    let path = root.join(req.path);
+    let metadata = req
+        .metadata
+        .ok_or_else(|| anyhow!("missing metadata on metadata request"))?;
+    apply_metadata(&path, &metadata)?;
+
+    Ok(TransferResponse {
+        id: req.id,
+        kind: TransferResponseKind::Ok,
+    })
+}
+
+fn handle_list(root: &Path, req: TransferRequest) -> anyhow::Result<TransferResponse> {
+    let after = match req.kind {
+        TransferRequestKind::List { after } => after,
+        _ => bail!("unexpected request kind in list"),
+    };
+    let path = root.join(&req.path);
+
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if atomic::is_temp_path(entry.file_name().as_ref()) {
+            continue;
+        }
+        let entry_path = req.path.join(entry.file_name());
+        if matches!(&after, Some(after) if entry_path <= *after) {
+            continue;
+        }
+        if let Some(file_type) = FileType::from_fs(entry.file_type()?) {
+            entries.push(proto::ListEntry {
+                path: entry_path,
+                file_type,
+            });
+        }
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+    // Every page holds at least one entry, so that listing always makes progress
+    let mut size = 0;
+    let page_len = entries
+        .iter()
+        .position(|entry| {
+            size += entry.path.as_os_str().len() + LIST_ENTRY_OVERHEAD;
+            size > LISTING_CHUNK_SIZE
+        })
+        .map_or(entries.len(), |len| len.max(1));
+    let more = page_len < entries.len();
+    entries.truncate(page_len);
+
+    Ok(TransferResponse {
+        id: req.id,
+        kind: TransferResponseKind::Listing { entries, more },
+    })
+}
+
+/// Sets permissions and times of a file, if they differ from the given metadata.
+///
+/// Note: Has to be called after the file is written and closed, otherwise the modification time
+/// is overwritten.
+fn apply_metadata(path: &Path, metadata: &Metadata) -> io::Result<()> {
+    let current = fs::metadata(path)?;
+    if current.permissions().mode() & 0o7777 != metadata.mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(metadata.mode))?;
+    }
+
+    let mtime = FileTime::from_unix_time(metadata.mtime.secs, metadata.mtime.nanos);
+    match metadata.atime {
+        Some(atime) => {
+            let atime = FileTime::from_unix_time(atime.secs, atime.nanos);
+            filetime::set_file_times(path, atime, mtime)?;
+        }
+        None if FileTime::from_last_modification_time(&current) != mtime => {
+            filetime::set_file_mtime(path, mtime)?;
+        }
+        None => (),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::{symlink, MetadataExt};
+
+    use tokio::io::duplex;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn request(path: &str, file_type: FileType, kind: TransferRequestKind) -> TransferRequest {
+        TransferRequest {
+            id: Uuid::new_v4(),
+            path: PathBuf::from(path),
+            file_type,
+            kind,
+            transfer: None,
+            link_target: None,
+            metadata: None,
+        }
+    }
+
+    fn frames(
+        max_frame_length: usize,
+    ) -> (
+        transport::Frames<BoxAsynRead, BoxAsynWrite>,
+        transport::Frames<BoxAsynRead, BoxAsynWrite>,
+    ) {
+        let (a, b) = duplex(64 * 1024);
+        let (a_read, a_write) = tokio::io::split(a);
+        let (b_read, b_write) = tokio::io::split(b);
+        (
+            transport::Frames::new(Box::pin(a_read), Box::pin(a_write), max_frame_length),
+            transport::Frames::new(Box::pin(b_read), Box::pin(b_write), max_frame_length),
+        )
+    }
+
+    #[test]
+    fn symlinks_outside_of_root_are_only_replaced() {
+        let root = TempDir::new();
+        let outside = TempDir::new();
+        symlink(outside.path(), root.path().join("link")).unwrap();
+
+        let replacing = [
+            (FileType::File, TransferRequestKind::Check),
+            (FileType::Dir, TransferRequestKind::Check),
+            (FileType::Symlink, TransferRequestKind::Check),
+            (FileType::File, TransferRequestKind::Contents),
+            (FileType::File, TransferRequestKind::Resume),
+            (FileType::File, TransferRequestKind::Delta),
+            (
+                FileType::File,
+                TransferRequestKind::Remove { recursive: true },
+            ),
+        ];
+        for (file_type, kind) in replacing {
+            let req = request("link", file_type, kind);
+            assert_eq!(validate_paths(root.path(), &req), Ok(()), "{:?}", req);
+        }
+
+        let reading = [
+            (FileType::File, TransferRequestKind::Metadata),
+            (FileType::Dir, TransferRequestKind::List { after: None }),
+            (FileType::File, TransferRequestKind::Signature { offset: 0 }),
+        ];
+        for (file_type, kind) in reading {
+            let req = request("link", file_type, kind);
+            assert_eq!(
+                validate_paths(root.path(), &req),
+                Err((PathBuf::from("link"), PathViolation::SymlinkOutsideRoot)),
+                "{:?}",
+                req
+            );
+        }
+
+        root.write("file", "");
+        let req = request("file", FileType::File, TransferRequestKind::Metadata);
+        assert_eq!(validate_paths(root.path(), &req), Ok(()));
+
+        // nothing is written below the symlink
+        let req = request("link/file", FileType::File, TransferRequestKind::Contents);
+        assert_eq!(
+            validate_paths(root.path(), &req),
+            Err((
+                PathBuf::from("link/file"),
+                PathViolation::SymlinkOutsideRoot
+            ))
+        );
+    }
+
+    #[test]
+    fn escaping_paths_are_rejected() {
+        let root = TempDir::new();
+        let req = request("../file", FileType::File, TransferRequestKind::Check);
+        assert_eq!(
+            validate_paths(root.path(), &req),
+            Err((PathBuf::from("../file"), PathViolation::ParentDir))
+        );
+
+        let req = request(
+            ".",
+            FileType::Dir,
+            TransferRequestKind::Remove { recursive: true },
+        );
+        assert_eq!(
+            validate_paths(root.path(), &req),
+            Err((PathBuf::from("."), PathViolation::Root))
+        );
+
+        let kind = TransferRequestKind::Rename {
+            new_path: PathBuf::from("/etc/passwd"),
+        };
+        let req = request("file", FileType::File, kind);
+        assert_eq!(
+            validate_paths(root.path(), &req),
+            Err((PathBuf::from("/etc/passwd"), PathViolation::Absolute))
+        );
+    }
+
+    #[tokio::test]
+    async fn large_listings_are_split_into_pages_fitting_into_frames() {
+        let root = TempDir::new();
+        fs::create_dir(root.path().join("dir")).unwrap();
+        let mut names: Vec<_> = (0..6000).map(|i| format!("{:0>250}", i)).collect();
+        for name in &names {
+            fs::File::create(root.path().join("dir").join(name)).unwrap();
+        }
+        names.sort();
+
+        let (mut a, mut b) = frames(transport::MIN_MAX_FRAME_LENGTH);
+        let mut listed = Vec::new();
+        let mut pages = 0;
+        loop {
+            let after = listed.last().map(|name| Path::new("dir").join(name));
+            let req = request("dir", FileType::Dir, TransferRequestKind::List { after });
+            let resp = handle_list(root.path(), req).unwrap();
+            let (sent, received) = tokio::join!(a.send(resp), b.recv::<TransferResponse>());
+            sent.unwrap();
+            pages += 1;
+            match received.unwrap().kind {
+                TransferResponseKind::Listing { entries, more } => {
+                    listed.extend(entries.into_iter().map(|entry| {
+                        assert_eq!(entry.file_type, FileType::File);
+                        entry.path.strip_prefix("dir").unwrap().to_owned()
+                    }));
+                    if !more {
+                        break;
+                    }
+                }
+                kind => panic!("unexpected response {:?}", kind),
+            }
+        }
+
+        assert!(pages > 1);
+        assert_eq!(listed, names.iter().map(PathBuf::from).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn renames_create_missing_parents() {
+        let root = TempDir::new();
+        root.write("from", "contents");
+        let kind = TransferRequestKind::Rename {
+            new_path: PathBuf::from("new/parent/to"),
+        };
+        let resp = handle_rename(root.path(), request("from", FileType::File, kind)).unwrap();
+        assert!(matches!(resp.kind, TransferResponseKind::Ok));
+        assert!(!root.path().join("from").exists());
+        assert_eq!(
+            fs::read(root.path().join("new/parent/to")).unwrap(),
+            b"contents"
+        );
+    }
+
+    #[test]
+    fn renames_of_missing_sources_need_contents() {
+        let root = TempDir::new();
+        let kind = TransferRequestKind::Rename {
+            new_path: PathBuf::from("to"),
+        };
+        let resp = handle_rename(root.path(), request("from", FileType::File, kind)).unwrap();
+        assert!(matches!(resp.kind, TransferResponseKind::NeedContents));
+        assert!(!root.path().join("to").exists());
+    }
+
+    fn hello(version: u32) -> proto::Hello {
+        proto::Hello {
+            version,
+            capabilities: Capabilities::SHA256.union(Capabilities::ZSTD),
+            compression_level: 0,
+            block_size: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn handshakes_with_the_same_version_are_accepted() {
+        let (mut client, mut handler) = frames(transport::DEFAULT_MAX_FRAME_LENGTH);
+        client.send(hello(proto::PROTOCOL_VERSION)).await.unwrap();
+        let (session, welcome) = tokio::join!(
+            handshake(&mut handler, None),
+            client.recv::<proto::Welcome>()
+        );
+        let requested = Capabilities::SHA256.union(Capabilities::ZSTD);
+        assert_eq!(session.unwrap().capabilities, requested);
+        match welcome.unwrap() {
+            proto::Welcome::Accepted {
+                capabilities,
+                challenge,
+                ..
+            } => {
+                assert_eq!(capabilities, requested);
+                assert_eq!(challenge, None);
+            }
+            welcome => panic!("unexpected welcome {:?}", welcome),
+        }
+    }
+
+    #[tokio::test]
+    async fn handshakes_with_other_versions_are_rejected() {
+        let (mut client, mut handler) = frames(transport::DEFAULT_MAX_FRAME_LENGTH);
+        client
+            .send(hello(proto::PROTOCOL_VERSION - 1))
+            .await
+            .unwrap();
+        let (session, welcome) = tokio::join!(
+            handshake(&mut handler, None),
+            client.recv::<proto::Welcome>()
+        );
+        assert!(session.is_err());
+        match welcome.unwrap() {
+            proto::Welcome::Rejected { version, reason } => {
+                assert_eq!(version, proto::PROTOCOL_VERSION);
+                assert!(reason.contains("protocol version mismatch"), "{}", reason);
+            }
+            welcome => panic!("unexpected welcome {:?}", welcome),
+        }
+    }
+
+    #[test]
+    fn symlinks_replace_other_entries() {
+        let root = TempDir::new();
+        let target = Path::new("target");
+        let file = root.write("file", "contents");
+        let dir = root.path().join("dir");
+        fs::create_dir(&dir).unwrap();
+        root.write("dir/file", "contents");
+        let link = root.path().join("link");
+        symlink("other", &link).unwrap();
+
+        for path in [&file, &dir, &link] {
+            let kind = handle_check_symlink(path, target).unwrap();
+            assert!(matches!(kind, TransferResponseKind::Ok));
+            assert_eq!(fs::read_link(path).unwrap(), target);
+        }
+
+        // an up to date symlink is kept
+        let inode = fs::symlink_metadata(&link).unwrap().ino();
+        handle_check_symlink(&link, target).unwrap();
+        assert_eq!(fs::symlink_metadata(&link).unwrap().ino(), inode);
+    }
+
+    #[test]
+    fn metadata_is_applied() {
+        let root = TempDir::new();
+        let path = root.write("file", "contents");
+        let mut metadata = Metadata {
+            mode: 0o640,
+            mtime: proto::Timestamp {
+                secs: 1_000_000_000,
+                nanos: 500,
+            },
+            atime: None,
+        };
+        apply_metadata(&path, &metadata).unwrap();
+        assert_eq!(
+            Metadata::from_fs(&fs::metadata(&path).unwrap(), false),
+            metadata
+        );
+
+        metadata.atime = Some(proto::Timestamp {
+            secs: 1_100_000_000,
+            nanos: 0,
+        });
+        apply_metadata(&path, &metadata).unwrap();
+        assert_eq!(
+            Metadata::from_fs(&fs::metadata(&path).unwrap(), true),
+            metadata
+        );
+    }
+
+    #[test]
+    fn only_recursive_removes_remove_non_empty_dirs() {
+        let root = TempDir::new();
+        let dir = root.path().join("dir");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        root.write("dir/sub/file", "contents");
+
+        let kind = TransferRequestKind::Remove { recursive: false };
+        assert!(handle_remove(root.path(), request("dir", FileType::Dir, kind)).is_err());
+        assert!(dir.join("sub/file").exists());
+
+        let kind = TransferRequestKind::Remove { recursive: true };
+        let resp = handle_remove(root.path(), request("dir", FileType::Dir, kind)).unwrap();
+        assert!(matches!(resp.kind, TransferResponseKind::Ok));
+        assert!(!dir.exists());
+
+        // removing again succeeds, since the path is gone either way
+        let kind = TransferRequestKind::Remove { recursive: true };
+        let resp = handle_remove(root.path(), request("dir", FileType::Dir, kind)).unwrap();
+        assert!(matches!(resp.kind, TransferResponseKind::Ok));
+    }
+
+    #[test]
+    fn empty_files_replace_existing_entries() {
+        let root = TempDir::new();
+        let outside = TempDir::new();
+        let target = outside.write("target", "contents");
+        let file = root.write("file", "contents");
+        let link = root.path().join("link");
+        symlink(&target, &link).unwrap();
+
+        for path in [&file, &link, &root.path().join("new")] {
+            create_empty_file(path, Durability::default()).unwrap();
+            let metadata = fs::symlink_metadata(path).unwrap();
+            assert!(metadata.is_file());
+            assert_eq!(metadata.len(), 0);
+        }
+        assert_eq!(fs::read(&target).unwrap(), b"contents");
+    }
+
+    #[test]
+    fn empty_files_are_checked_by_shasum() {
+        let root = TempDir::new();
+        let path = root.write("file", "");
+        let transfer = |shasum| Transfer {
+            kind: TransferKind::Contents,
+            data: Vec::new(),
+            shasum,
+            file_size: Some(0),
+            data_size: None,
+            offset: 0,
+            compressed: false,
+            last: false,
+        };
+
+        let kind = handle_check_file(&path, transfer(shasum_bytes([])), None, None).unwrap();
+        assert!(matches!(kind, TransferResponseKind::Ok));
+        let kind = handle_check_file(&path, transfer(shasum_bytes(b"x")), None, None).unwrap();
+        assert!(matches!(kind, TransferResponseKind::NeedContents));
+    }
+}
@@ -1,27 +1,48 @@
+use std::collections::{HashSet, VecDeque};
 use std::env::current_dir;
 use std::fmt::Debug;
 use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::{fs, io};
 
 use anyhow::{anyhow, bail, Context as _};
 use argh::FromArgs;
 use fast_rsync::{diff, Signature};
 use futures_util::future::poll_fn;
-use ignore::{DirEntry, WalkBuilder};
 use memmap2::Mmap;
-use notify::event::{CreateKind, ModifyKind, RemoveKind, RenameMode};
-use notify::{Event, EventKind, RecursiveMode, Watcher};
+use notify::event::{CreateKind, Flag, ModifyKind, RemoveKind, RenameMode};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use syncd::debounce::Debouncer;
 use syncd::ignore::Ignore;
-use syncd::{init, mmap_with_shasum, proto, transport, BoxAsynRead, BoxAsynWrite};
+use syncd::pathutil::is_symlink;
+use syncd::poll::{self, Scanner};
+use syncd::tls;
+use syncd::write::ChunkWriter;
+use syncd::{auth, compress, signature};
+use syncd::{init, mmap_with_shasum, proto, shasum_bytes, transport, BoxAsynRead, BoxAsynWrite};
 use tokio::net::TcpStream;
 use tokio::process::Command;
 use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
 use tokio_tower::pipeline;
 use tower::Service;
 use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
-const FILE_CHUNK_SIZE: usize = 1024 * 1024; // 1 MB
+const FILE_CHUNK_SIZE: usize = transport::MAX_CHUNK_SIZE;
+
+/// Time to wait for the second half of a rename reported as two events
+const RENAME_PAIRING_WINDOW: Duration = Duration::from_millis(500);
+
+/// Capabilities requested from the handler
+const CAPABILITIES: proto::Capabilities = proto::Capabilities::SHA256
+    .union(proto::Capabilities::SYMLINKS)
+    .union(proto::Capabilities::METADATA)
+    .union(proto::Capabilities::LIST)
+    .union(proto::Capabilities::RESUME);
 
 /// Transfer directory structure via transfer-handler
 #[derive(Debug, FromArgs)]
@@ -44,12 +65,187 @@ struct Args {
     /// don't respect .ignore files
     #[argh(switch)]
     no_ignore_dot: bool,
+    /// skip paths matching the gitignore glob; can be repeated
+    #[argh(option)]
+    exclude: Vec<String>,
+    /// transfer paths matching the gitignore glob even if ignored otherwise; can be repeated
+    #[argh(option)]
+    include: Vec<String>,
+    /// preserve access times in addition to modification times
+    #[argh(switch)]
+    atime: bool,
+    /// delete files in the destination which do not exist in the source
+    #[argh(switch)]
+    delete: bool,
+    /// with --delete, also delete files in the destination which are ignored in the source
+    #[argh(switch)]
+    delete_excluded: bool,
+    /// block size of rsync signatures [default: square root of the file size]
+    #[argh(option, from_str_fn(signature::parse_block_size))]
+    block_size: Option<u32>,
+    /// compress transferred data with zstd at the given level (0 is the zstd default)
+    #[argh(option)]
+    compress: Option<i32>,
+    /// PEM CA certificates to verify the handler with; enables TLS for --connect
+    #[argh(option)]
+    tls_ca: Option<PathBuf>,
+    /// PEM client certificate chain for mutual TLS
+    #[argh(option)]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of the client certificate
+    #[argh(option)]
+    tls_key: Option<PathBuf>,
+    /// name to verify the handler certificate against [default: host of --connect]
+    #[argh(option)]
+    tls_server_name: Option<String>,
+    /// maximum length of a protocol frame in bytes, at least 1114112 [default: 8388608]
+    #[argh(
+        option,
+        default = "transport::DEFAULT_MAX_FRAME_LENGTH",
+        from_str_fn(transport::parse_max_frame_length)
+    )]
+    max_frame_length: usize,
+    /// file with the pre-shared key to authenticate with [default: $SYNCD_PSK]
+    #[argh(option)]
+    psk_file: Option<PathBuf>,
+    /// milliseconds without events before changes are transferred; 0 disables coalescing
+    /// [default: 100]
+    #[argh(option, default = "100")]
+    settle: u64,
+    /// seconds after which the whole directory is walked again, in case events were missed
+    #[argh(option)]
+    rescan_interval: Option<u64>,
+    /// scan for changes every given milliseconds instead of watching, e.g. on NFS or SSHFS
+    #[argh(option, from_str_fn(poll::parse_interval))]
+    poll: Option<u64>,
+    /// print which ignore rule applies to the given path and exit
+    #[argh(option)]
+    explain_ignore: Option<PathBuf>,
+}
+
+/// Settings of the session with the handler
+#[derive(Debug)]
+struct Session {
+    /// capabilities enabled by the handshake
+    capabilities: proto::Capabilities,
+    /// transfer access times
+    atime: bool,
+    /// zstd level if compression is enabled
+    compression_level: Option<i32>,
+    compression_stats: compress::Stats,
+}
+
+impl Session {
+    /// Returns the metadata to transfer for a file, if supported by the handler.
+    fn metadata(&self, metadata: &fs::Metadata) -> Option<proto::Metadata> {
+        if self.capabilities.contains(proto::Capabilities::METADATA) {
+            Some(proto::Metadata::from_fs(metadata, self.atime))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the data of a chunk to send and whether it is compressed.
+    ///
+    /// Incompressible chunks are sent raw.
+    fn compress(&self, chunk: &[u8]) -> io::Result<(Vec<u8>, bool)> {
+        let compressed = match self.compression_level {
+            Some(level) => compress::compress(chunk, level)?,
+            None => None,
+        };
+        let data = match compressed {
+            Some(compressed) => (compressed, true),
+            None => (chunk.to_vec(), false),
+        };
+        self.compression_stats.record(chunk.len(), data.0.len());
+        Ok(data)
+    }
+
+    fn log_compression_stats(&self) {
+        if self.compression_level.is_some() {
+            let stats = &self.compression_stats;
+            info!(
+                raw_bytes = stats.raw_bytes(),
+                sent_bytes = stats.sent_bytes(),
+                saved_bytes = stats.saved_bytes(),
+                "compression"
+            );
+        }
+    }
+}
+
+/// Memory mapped local file together with the data describing it
+struct MappedFile {
+    mmap: Mmap,
+    shasum: [u8; 32],
+    metadata: Option<proto::Metadata>,
+}
+
+impl MappedFile {
+    fn open(path: &Path, session: &Session) -> anyhow::Result<Self> {
+        let metadata = session.metadata(&fs::metadata(path)?);
+        let (mmap, shasum) = mmap_with_shasum(path)?;
+        Ok(Self {
+            mmap,
+            shasum,
+            metadata,
+        })
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args: Args = init();
 
+    if args.delete_excluded && !args.delete {
+        bail!("--delete-excluded requires --delete");
+    }
+    let dir = args
+        .root
+        .clone()
+        .map(Ok)
+        .unwrap_or_else(current_dir)
+        .context("failed to use current working directory as root")?;
+    let dir = dir.canonicalize()?;
+
+    let mut ignore = Ignore::new(dir.clone());
+    ignore.hidden(args.hidden).no_ignore_dot(args.no_ignore_dot);
+    for glob in &args.exclude {
+        ignore.exclude(glob);
+    }
+    for glob in &args.include {
+        ignore.include(glob);
+    }
+    let ignore = Arc::new(ignore.build().context("invalid ignore rules")?);
+    debug!(?ignore, "ignore list");
+
+    if let Some(path) = &args.explain_ignore {
+        let path = path
+            .canonicalize()
+            .with_context(|| format!("failed to resolve {}", path.display()))?;
+        if !path.starts_with(&dir) {
+            bail!("{} is not inside of {}", path.display(), dir.display());
+        }
+        let explanation = ignore.explain(&path, path.is_dir());
+        println!("{}: {}", path.display(), explanation);
+        return Ok(());
+    }
+
+    let client_auth = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some((cert.as_path(), key.as_path())),
+        (None, None) => None,
+        _ => bail!("--tls-cert and --tls-key have to be given together"),
+    };
+    if args.tls_ca.is_none() && (client_auth.is_some() || args.tls_server_name.is_some()) {
+        bail!("TLS options require --tls-ca");
+    }
+    if args.tls_ca.is_some() && args.connect.is_none() {
+        bail!("TLS requires --connect");
+    }
+
+    let secret =
+        auth::read_secret(args.psk_file.as_deref()).context("failed to read pre-shared key")?;
+
     let (read, write): (BoxAsynRead, BoxAsynWrite) = if let Some(handler_cmd) = args.handler_cmd {
         let dest = args
             .dest
@@ -69,67 +265,332 @@ async fn main() -> anyhow::Result<()> {
             .ok_or_else(|| anyhow!("failed to open stdout of client"))?;
         (Box::pin(stdout), Box::pin(stdin))
     } else if let Some(connect) = args.connect {
-        let stream = TcpStream::connect(connect).await?;
-        let (read, write) = stream.into_split();
-        (Box::pin(read), Box::pin(write))
+        let stream = TcpStream::connect(&connect).await?;
+        if let Some(ca) = args.tls_ca.as_ref() {
+            let connector = tls::connector(ca, client_auth).context("failed to set up TLS")?;
+            let server_name = args
+                .tls_server_name
+                .as_deref()
+                .unwrap_or_else(|| host(&connect));
+            let stream = connector
+                .connect(tls::server_name(server_name)?, stream)
+                .await
+                .context("TLS handshake failed")?;
+            let (read, write) = tokio::io::split(stream);
+            (Box::pin(read), Box::pin(write))
+        } else {
+            let (read, write) = stream.into_split();
+            (Box::pin(read), Box::pin(write))
+        }
     } else {
         bail!("either --handler-cmd or --socket must be specified");
     };
 
-    let transport =
-        transport::BincodeTransport::<proto::TransferResponse, proto::TransferRequest, _, _>::new(
-            read, write,
-        );
+    let mut frames = transport::Frames::new(read, write, args.max_frame_length);
+    let hello = proto::Hello {
+        version: proto::PROTOCOL_VERSION,
+        capabilities: if args.compress.is_some() {
+            CAPABILITIES.union(proto::Capabilities::ZSTD)
+        } else {
+            CAPABILITIES
+        },
+        compression_level: args.compress.unwrap_or_default(),
+        block_size: args.block_size,
+    };
+    let (capabilities, compression_level) =
+        handshake(&mut frames, hello, secret.as_deref()).await?;
+    info!(?capabilities, "connected");
+    if args.delete && !capabilities.contains(proto::Capabilities::LIST) {
+        bail!("--delete is not supported by the handler");
+    }
+    let compression_level = if capabilities.contains(proto::Capabilities::ZSTD) {
+        info!(level = compression_level, "compressing with zstd");
+        Some(compression_level)
+    } else {
+        if args.compress.is_some() {
+            warn!("compression is not supported by the handler");
+        }
+        None
+    };
+    let session = Session {
+        capabilities,
+        atime: args.atime,
+        compression_level,
+        compression_stats: Default::default(),
+    };
+
+    let transport = frames.into_transport::<proto::TransferResponse, proto::TransferRequest>();
     let mut client = pipeline::Client::<_, tokio_tower::Error<_, _>, _>::with_error_handler(
         transport,
         |e| error!(reason = %e, "client failed"),
     );
 
-    let dir = args
-        .root
-        .map(Ok)
-        .unwrap_or_else(current_dir)
-        .context("failed to use current working directory as root")?;
-    let dir = dir.canonicalize()?;
-
     info!("initial sync");
-    initial_sync(&dir, &mut client, args.hidden).await?;
+    initial_sync(&dir, &dir, &mut client, &ignore, &session).await?;
+    session.log_compression_stats();
+    if args.delete {
+        info!("deleting extraneous files");
+        delete_extraneous(&dir, &dir, &mut client, &ignore, args.delete_excluded).await?;
+    }
 
-    let (tx, mut rx) = mpsc::channel(1);
-    let mut watcher = notify::recommended_watcher(move |event| {
-        let _ = tx.blocking_send(event);
-    })?;
-
-    watcher
-        .watch(&dir, RecursiveMode::Recursive)
-        .context("failed to initialize watcher")?;
-    info!(dir = %dir.display(), "watching");
-
-    let ignore = Ignore::new(dir.clone())
-        .hidden(args.hidden)
-        .no_ignore_dot(args.no_ignore_dot)
-        .build()?;
-    debug!(?ignore, "ignore list");
+    // unbounded, so that the watcher is never blocked and the kernel queue does not overflow; in
+    // exchange, events arriving faster than they are transferred are buffered without limit
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    // the watcher stops when dropped
+    let _watcher: Option<RecommendedWatcher> = match args.poll {
+        Some(interval) => {
+            let (root, ignore) = (dir.clone(), ignore.clone());
+            // the scanner takes its initial snapshot on its own thread, not on the runtime
+            thread::spawn(move || {
+                Scanner::new(root, ignore).run(Duration::from_millis(interval), |event| {
+                    tx.send(Ok(event)).is_ok()
+                })
+            });
+            info!(dir = %dir.display(), interval, "polling");
+            None
+        }
+        None => {
+            let mut watcher = notify::recommended_watcher(move |event| {
+                let _ = tx.send(event);
+            })?;
+            watcher
+                .watch(&dir, RecursiveMode::Recursive)
+                .context("failed to initialize watcher")?;
+            info!(dir = %dir.display(), "watching");
+            Some(watcher)
+        }
+    };
 
-    while let Some(event) = rx.recv().await {
-        let event = event.context("watcher failed")?;
-        match handle_fs_event(&mut client, &ignore, event, &dir).await {
-            Ok(Ok(())) => (),
-            Ok(e) => return e, // fatal error
-            Err(e) => {
-                // handling error
-                warn!(reason = %e, "event handler failed");
+    let mut state = WatchState::default();
+    let mut debouncer = Debouncer::new(Duration::from_millis(args.settle));
+    let rescan_interval = args.rescan_interval.map(Duration::from_secs);
+    let mut next_rescan = rescan_interval.map(|interval| Instant::now() + interval);
+    let (delete, delete_excluded) = (args.delete, args.delete_excluded);
+    loop {
+        let deadline = [
+            state.renames.next_deadline(),
+            debouncer.deadline(),
+            next_rescan,
+        ]
+        .iter()
+        .flatten()
+        .min()
+        .copied();
+        let recv = async {
+            match deadline {
+                Some(deadline) => time::timeout_at(deadline, rx.recv()).await.ok(),
+                None => Some(rx.recv().await),
+            }
+        };
+        let next = tokio::select! {
+            next = recv => next,
+            _ = tokio::signal::ctrl_c() => {
+                info!("interrupted");
+                break;
+            }
+        };
+        let events = match next {
+            Some(Some(event)) if args.settle == 0 => vec![event.context("watcher failed")?],
+            Some(Some(event)) => {
+                debouncer.push(event.context("watcher failed")?);
+                continue;
+            }
+            Some(None) => break,
+            // pending events are processed before renames expire, since they might complete them
+            None if debouncer.deadline().is_some() => debouncer.drain(),
+            None if state.renames.is_expired() => state.renames.pop_expired().into_iter().collect(),
+            None => {
+                info!("periodic rescan");
+                rescan(
+                    &mut client,
+                    &dir,
+                    &dir,
+                    delete,
+                    delete_excluded,
+                    &ignore,
+                    &session,
+                )
+                .await;
+                next_rescan = rescan_interval.map(|interval| Instant::now() + interval);
+                continue;
+            }
+        };
+
+        // changed rules apply to all events of the batch
+        let changed_dirs = reload_ignore_files(&ignore, &events);
+        state.created = events
+            .iter()
+            .filter(|event| matches!(event.kind, EventKind::Create(_)))
+            .flat_map(|event| event.paths.iter().cloned())
+            .collect();
+
+        // a full rescan covers the remaining rescans of the batch
+        let mut rescanned = false;
+        for event in events {
+            if event.flag() == Some(Flag::Rescan) || event.kind == EventKind::Other {
+                if rescanned {
+                    continue;
+                }
+                warn!(?event, "watcher missed events, rescanning");
+                rescanned = event.paths.is_empty();
+                let paths = match event.paths.as_slice() {
+                    [] => vec![dir.clone()],
+                    paths => paths.to_vec(),
+                };
+                for path in paths.iter().filter(|path| path.starts_with(&dir)) {
+                    rescan(
+                        &mut client,
+                        &dir,
+                        path,
+                        delete,
+                        delete_excluded,
+                        &ignore,
+                        &session,
+                    )
+                    .await;
+                }
+                continue;
+            }
+            let res =
+                handle_fs_event(&mut client, &ignore, event, &dir, &session, &mut state).await;
+            match res {
+                Ok(Ok(())) => (),
+                Ok(e) => return e, // fatal error
+                Err(e) => {
+                    // handling error
+                    warn!(reason = %e, "event handler failed");
+                }
+            }
+        }
+        state.removed_dirs.clear();
+
+        // newly included entries are transferred, and with --delete-excluded newly ignored
+        // entries are removed
+        if !rescanned {
+            for path in &changed_dirs {
+                info!(path = %path.display(), "ignore rules changed, rescanning");
+                rescan(
+                    &mut client,
+                    &dir,
+                    path,
+                    delete,
+                    delete_excluded,
+                    &ignore,
+                    &session,
+                )
+                .await;
             }
         }
     }
+    session.log_compression_stats();
     Ok(())
 }
 
+/// Reloads the ignore files changed by the events and returns the directories whose entries are
+/// affected, without those covered by others.
+fn reload_ignore_files(ignore: &Ignore, events: &[Event]) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    for event in events {
+        if matches!(
+            event.kind,
+            EventKind::Remove(RemoveKind::Folder | RemoveKind::Any)
+                | EventKind::Modify(ModifyKind::Name(_))
+        ) {
+            for path in &event.paths {
+                ignore.forget(path);
+            }
+        }
+        for path in event
+            .paths
+            .iter()
+            .filter(|path| ignore.is_ignore_file(path))
+        {
+            match ignore.reload(path) {
+                Ok(dir) => dirs.push(dir),
+                Err(e) => {
+                    warn!(path = %path.display(), reason = %e, "failed to reload ignore file")
+                }
+            }
+        }
+    }
+    dirs.sort();
+    dirs.dedup_by(|dir, parent| dir.starts_with(parent));
+    dirs
+}
+
+/// Returns the host part of a `host:port` address.
+fn host(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map(|(host, _)| host).unwrap_or(addr);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Negotiates the protocol version and capabilities with the handler.
+///
+/// Answers the challenge of the handler if it requires a pre-shared key. Returns the capabilities
+/// enabled for this session and the compression level.
+async fn handshake(
+    frames: &mut transport::Frames<BoxAsynRead, BoxAsynWrite>,
+    hello: proto::Hello,
+    secret: Option<&[u8]>,
+) -> anyhow::Result<(proto::Capabilities, i32)> {
+    frames
+        .send(hello)
+        .await
+        .context("failed to send handshake")?;
+    let welcome: proto::Welcome = frames
+        .recv()
+        .await
+        .context("handshake failed (is transfer-handler up to date?)")?;
+    debug!(?welcome, "handshake");
+
+    match welcome {
+        proto::Welcome::Accepted {
+            version,
+            capabilities,
+            compression_level,
+            challenge,
+        } => {
+            if version != proto::PROTOCOL_VERSION {
+                bail!(
+                    "protocol version mismatch: transfer speaks {}, transfer-handler speaks {}",
+                    proto::PROTOCOL_VERSION,
+                    version
+                );
+            }
+            if !capabilities.contains(proto::Capabilities::SHA256) {
+                bail!("handler does not support sha256");
+            }
+            if let Some(nonce) = challenge {
+                let secret = secret.ok_or_else(|| {
+                    anyhow!(
+                        "handler requires authentication, use --psk-file or {}",
+                        auth::PSK_ENV
+                    )
+                })?;
+                let authenticate = proto::Authenticate {
+                    answer: auth::respond(secret, &nonce),
+                };
+                frames.send(authenticate).await?;
+                match frames.recv().await.context("authentication failed")? {
+                    proto::AuthResult::Accepted => debug!("authenticated"),
+                    proto::AuthResult::Rejected => {
+                        bail!("authentication failed: pre-shared key was rejected")
+                    }
+                }
+            }
+            Ok((capabilities, compression_level))
+        }
+        proto::Welcome::Rejected { reason, .. } => bail!("handler rejected session: {}", reason),
+    }
+}
+
 async fn handle_fs_event<E, S>(
     client: &mut S,
     ignore: &Ignore,
     event: Event,
     root: &Path,
+    session: &Session,
+    state: &mut WatchState,
 ) -> anyhow::Result<anyhow::Result<()>>
 where
     E: std::error::Error + Sync + Send + 'static,
@@ -139,50 +600,98 @@ where
     let p1 = paths.next();
     let p2 = paths.next();
 
-    match (event.kind.clone(), p1, p2) {
+    match (event.kind, p1, p2) {
         (EventKind::Create(CreateKind::Folder), Some(path), _)
             if !ignore.should_skip_path(&path) =>
         {
+            // a directory moved in from outside of the tree is reported as created without its
+            // entries, and entries created before the directory was watched are not reported
             info!(path = %path.display(), "create dir");
-            check_dir(client, root, &path).await
+            check_tree(client, root, &path, ignore, session, &state.created).await
         }
         (EventKind::Create(CreateKind::File), Some(path), _) if !ignore.should_skip_path(&path) => {
-            info!(path = %path.display(), "create file");
-            transfer_contents(client, root, &path).await
+            if is_symlink(&path) {
+                info!(path = %path.display(), "create symlink");
+                check_symlink(client, root, &path, session).await
+            } else {
+                info!(path = %path.display(), "create file");
+                transfer_contents(client, root, &path, session).await
+            }
         }
         (EventKind::Modify(ModifyKind::Data(_)), Some(path), _)
             if !ignore.should_skip_path(&path) =>
         {
             info!(path = %path.display(), "modify");
-            check_file(client, root, &path).await
+            if is_symlink(&path) {
+                check_symlink(client, root, &path, session).await
+            } else {
+                check_file(client, root, &path, session).await
+            }
         }
-        (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), Some(from), Some(to)) => {
-            let is_dir = from.is_dir();
-            if ignore.should_skip_path(&from) && !ignore.should_skip_path(&to) {
-                if is_dir {
-                    info!(path = %to.display(), "create dir");
-                    check_dir(client, root, &to).await
-                } else {
-                    info!(path = %to.display(), "modify");
-                    check_file(client, root, &to).await
+        (EventKind::Modify(ModifyKind::Metadata(_)), Some(path), _)
+            if !ignore.should_skip_path(&path) =>
+        {
+            info!(path = %path.display(), "metadata");
+            transfer_metadata(client, root, &path, session).await
+        }
+        (EventKind::Modify(ModifyKind::Name(RenameMode::From)), Some(from), _) => {
+            debug!(path = %from.display(), "waiting for rename target");
+            state.renames.push_from(event.tracker(), from);
+            Ok(Ok(()))
+        }
+        (EventKind::Modify(ModifyKind::Name(RenameMode::To)), Some(to), _) => {
+            match state.renames.pair_to(event.tracker()) {
+                Some(from) => {
+                    handle_rename(client, ignore, root, session, &from, &to, &state.created).await
                 }
-            } else if !ignore.should_skip_path(&to) {
-                info!(from = %from.display(), to = %to.display(), "rename");
-                handle_event_rename(client, from, to).await
-            } else {
-                debug!(?event, "skipping");
+                None if !ignore.should_skip_path(&to) => {
+                    info!(path = %to.display(), "moved in");
+                    check_tree(client, root, &to, ignore, session, &state.created).await
+                }
+                None => {
+                    debug!(?event, "skipping");
+                    Ok(Ok(()))
+                }
+            }
+        }
+        (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), Some(from), Some(to)) => {
+            if state.renames.take_paired(event.tracker()) {
+                debug!(?event, "rename already handled");
                 Ok(Ok(()))
+            } else {
+                handle_rename(client, ignore, root, session, &from, &to, &state.created).await
             }
         }
+        (EventKind::Remove(_), Some(path), _) if state.removed_dirs.covers(&path) => {
+            debug!(path = %path.display(), "already removed with parent");
+            Ok(Ok(()))
+        }
+        // removed paths don't exist anymore, so their type has to be given for dir-only rules
         (EventKind::Remove(RemoveKind::Folder), Some(path), _)
-            if !ignore.should_skip_path(&path) =>
+            if !ignore.should_skip(&path, true) =>
         {
             info!(path = %path.display(), "remove dir");
-            handle_event_remove(client, path, true).await
+            let res = handle_event_remove(client, root, &path, true, true).await;
+            if let Ok(Ok(())) = res {
+                state.removed_dirs.insert(path);
+            }
+            res
         }
-        (EventKind::Remove(RemoveKind::File), Some(path), _) if !ignore.should_skip_path(&path) => {
+        (EventKind::Remove(RemoveKind::File), Some(path), _)
+            if !ignore.should_skip(&path, false) =>
+        {
             info!(path = %path.display(), "remove file");
-            handle_event_remove(client, path, false).await
+            handle_event_remove(client, root, &path, false, false).await
+        }
+        (EventKind::Remove(RemoveKind::Any), Some(path), _)
+            if !ignore.should_skip(&path, false)
+                && !ignore.should_skip(&path, true)
+                && fs::symlink_metadata(&path).is_err() =>
+        {
+            // the type is unknown, e.g. after the path was moved out of the tree; the handler
+            // removes whatever is at the path
+            info!(path = %path.display(), "remove");
+            handle_event_remove(client, root, &path, false, true).await
         }
         _ => {
             debug!(?event, "skipping");
@@ -191,10 +700,127 @@ where
     }
 }
 
+/// State of the watcher spanning multiple events
+#[derive(Debug, Default)]
+struct WatchState {
+    removed_dirs: RemovedDirs,
+    renames: RenameTracker,
+    /// paths created in the current batch, which are transferred by their own events
+    created: HashSet<PathBuf>,
+}
+
+/// Pairs the halves of renames which are reported as separate `From` and `To` events.
+///
+/// A `From` without a matching `To` within `RENAME_PAIRING_WINDOW` was moved out of the tree and
+/// expires as a removal. A `To` without a matching `From` was moved into the tree.
+#[derive(Debug, Default)]
+struct RenameTracker {
+    pending: VecDeque<PendingRename>,
+    /// trackers of renames handled by their halves, whose `Both` event is skipped
+    paired: VecDeque<usize>,
+}
+
+#[derive(Debug)]
+struct PendingRename {
+    tracker: Option<usize>,
+    from: PathBuf,
+    deadline: Instant,
+}
+
+impl RenameTracker {
+    /// Number of handled trackers to remember
+    const MAX_PAIRED: usize = 64;
+
+    fn push_from(&mut self, tracker: Option<usize>, from: PathBuf) {
+        self.pending.push_back(PendingRename {
+            tracker,
+            from,
+            deadline: Instant::now() + RENAME_PAIRING_WINDOW,
+        });
+    }
+
+    /// Returns the source path of the rename completed by a `To` event.
+    ///
+    /// Without trackers, the oldest pending `From` is taken.
+    fn pair_to(&mut self, tracker: Option<usize>) -> Option<PathBuf> {
+        let pos = self.pending.iter().position(|p| p.tracker == tracker)?;
+        let pending = self.pending.remove(pos)?;
+        if let Some(tracker) = tracker {
+            if self.paired.len() == Self::MAX_PAIRED {
+                self.paired.pop_front();
+            }
+            self.paired.push_back(tracker);
+        }
+        Some(pending.from)
+    }
+
+    /// Returns true if the rename of a `Both` event was already handled by its halves.
+    fn take_paired(&mut self, tracker: Option<usize>) -> bool {
+        let tracker = match tracker {
+            Some(tracker) => tracker,
+            None => return false,
+        };
+        // the `From` half is superseded by the `Both` event
+        self.pending.retain(|p| p.tracker != Some(tracker));
+        match self.paired.iter().position(|&t| t == tracker) {
+            Some(pos) => {
+                self.paired.remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.pending.front().map(|p| p.deadline)
+    }
+
+    fn is_expired(&self) -> bool {
+        self.next_deadline()
+            .is_some_and(|deadline| deadline <= Instant::now())
+    }
+
+    /// Turns the oldest pending `From` into a removal event.
+    fn pop_expired(&mut self) -> Option<Event> {
+        let pending = self.pending.pop_front()?;
+        Some(Event::new(EventKind::Remove(RemoveKind::Any)).add_path(pending.from))
+    }
+}
+
+/// Directories removed recursively on the handler side
+///
+/// Removal events of their children are covered by the recursive removal and are not sent again.
+/// The directories are only kept for the current batch of events, which contains the removals of
+/// their children.
+#[derive(Debug, Default)]
+struct RemovedDirs(Vec<PathBuf>);
+
+impl RemovedDirs {
+    fn insert(&mut self, path: PathBuf) {
+        // forget directories which are covered by the new one or which were recreated
+        self.0
+            .retain(|dir| !dir.starts_with(&path) && fs::symlink_metadata(dir).is_err());
+        self.0.push(path);
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    /// Returns true if an ancestor of the path was removed and was not recreated since.
+    fn covers(&self, path: &Path) -> bool {
+        self.0
+            .iter()
+            .any(|dir| path != dir && path.starts_with(dir) && fs::symlink_metadata(dir).is_err())
+    }
+}
+
 async fn handle_event_remove<E, S>(
     client: &mut S,
-    path: PathBuf,
+    root: &Path,
+    path: &Path,
     is_dir: bool,
+    recursive: bool,
 ) -> anyhow::Result<anyhow::Result<()>>
 where
     E: std::error::Error + Sync + Send + 'static,
@@ -205,20 +831,67 @@ where
     } else {
         proto::FileType::File
     };
+    let relative_path = path.strip_prefix(root)?;
     let req = proto::TransferRequest {
         id: Uuid::new_v4(),
-        path,
+        path: relative_path.into(),
         file_type,
-        kind: proto::TransferRequestKind::Remove,
+        kind: proto::TransferRequestKind::Remove { recursive },
         transfer: None,
+        link_target: None,
+        metadata: None,
     };
     send_request(client, req).await
 }
 
+/// Handles a rename inside of the tree, where either side can be ignored.
+async fn handle_rename<E, S>(
+    client: &mut S,
+    ignore: &Ignore,
+    root: &Path,
+    session: &Session,
+    from: &Path,
+    to: &Path,
+    created: &HashSet<PathBuf>,
+) -> anyhow::Result<anyhow::Result<()>>
+where
+    E: std::error::Error + Sync + Send + 'static,
+    S: Service<proto::TransferRequest, Response = proto::TransferResponse, Error = E>,
+{
+    let is_dir = to.is_dir();
+    match (
+        ignore.should_skip(from, is_dir),
+        ignore.should_skip(to, is_dir),
+    ) {
+        (true, false) => {
+            info!(path = %to.display(), "moved in");
+            check_tree(client, root, to, ignore, session, created).await
+        }
+        (false, false) => {
+            info!(from = %from.display(), to = %to.display(), "rename");
+            handle_event_rename(client, ignore, root, session, from, to, created).await
+        }
+        (false, true) => {
+            info!(path = %from.display(), "moved out");
+            handle_event_remove(client, root, from, is_dir, true).await
+        }
+        (true, true) => {
+            debug!(from = %from.display(), to = %to.display(), "skipping rename");
+            Ok(Ok(()))
+        }
+    }
+}
+
+/// Renames the path on the handler side, or transfers the new path if the handler is missing the
+/// source.
 async fn handle_event_rename<E, S>(
     client: &mut S,
-    from: PathBuf,
-    to: PathBuf,
+    ignore: &Ignore,
+    root: &Path,
+    session: &Session,
+    from: &Path,
+    to: &Path,
+    created: &HashSet<PathBuf>,
 ) -> anyhow::Result<anyhow::Result<()>>
 where
     E: std::error::Error + Sync + Send + 'static,
@@ -226,27 +899,87 @@ where
 {
     let req = proto::TransferRequest {
         id: Uuid::new_v4(),
-        path: from,
+        path: from.strip_prefix(root)?.into(),
         file_type: proto::FileType::File, // does not matter
-        kind: proto::TransferRequestKind::Rename { new_path: to },
+        kind: proto::TransferRequestKind::Rename {
+            new_path: to.strip_prefix(root)?.into(),
+        },
         transfer: None,
+        link_target: None,
+        metadata: None,
     };
-    send_request(client, req).await
+
+    let resp = match send(client, req).await {
+        Ok(resp) => resp,
+        Err(e) => return Ok(Err(e.into())),
+    };
+
+    match resp.kind {
+        proto::TransferResponseKind::Ok => Ok(Ok(())),
+        proto::TransferResponseKind::NeedContents => {
+            debug!(path = %to.display(), "rename source missing on handler");
+            check_tree(client, root, to, ignore, session, created).await
+        }
+        proto::TransferResponseKind::CantHandle { reason } => {
+            bail!("handler failed: {}", reason);
+        }
+        proto::TransferResponseKind::InvalidPath { path, violation } => {
+            bail!("handler refused {}: {}", path.display(), violation);
+        }
+        kind => Ok(Err(anyhow!("protocol violation: got {:?}", kind))),
+    }
+}
+
+/// Transfers the subtree at `path` again after events might have been missed.
+///
+/// The closest existing directory is walked, and with `--delete` entries missing in the source
+/// are removed from the destination. Failures are logged, so that they don't stop watching.
+async fn rescan<E, S>(
+    client: &mut S,
+    root: &Path,
+    path: &Path,
+    delete: bool,
+    delete_excluded: bool,
+    ignore: &Arc<Ignore>,
+    session: &Session,
+) where
+    E: std::error::Error + Sync + Send + 'static,
+    S: Service<proto::TransferRequest, Response = proto::TransferResponse, Error = E>,
+{
+    let dir = path
+        .ancestors()
+        .take_while(|dir| dir.starts_with(root))
+        .find(|dir| dir.is_dir())
+        .unwrap_or(root);
+    debug!(path = %dir.display(), "rescan");
+    let mut res = initial_sync(root, dir, client, ignore, session).await;
+    if res.is_ok() && delete {
+        res = delete_extraneous(root, dir, client, ignore, delete_excluded).await;
+    }
+    if let Err(e) = res {
+        warn!(path = %dir.display(), reason = %e, "rescan failed");
+    }
+    session.log_compression_stats();
 }
 
-async fn initial_sync<E, S>(dir: &Path, client: &mut S, include_hidden: bool) -> anyhow::Result<()>
+/// Walks the directory at `path` inside of `root` and transfers all entries.
+async fn initial_sync<E, S>(
+    root: &Path,
+    path: &Path,
+    client: &mut S,
+    ignore: &Arc<Ignore>,
+    session: &Session,
+) -> anyhow::Result<()>
 where
     E: std::error::Error + Sync + Send + 'static,
     S: Service<proto::TransferRequest, Response = proto::TransferResponse, Error = E>,
 {
-    let mut builder = WalkBuilder::new(dir);
-    builder.hidden(!include_hidden);
-    let walk = builder.build();
+    let walk = ignore.walk_builder(path).build();
 
     for entry in walk {
         match entry {
             Ok(entry) => {
-                match handle_entry(client, dir, &entry).await {
+                match handle_entry(client, root, entry.path(), session).await {
                     Ok(Ok(())) => (),
                     Ok(e) => return e, // fatal error
                     Err(e) => {
@@ -261,6 +994,151 @@ where
     Ok(())
 }
 
+/// Removes all entries below the directory at `path` from the destination which do not exist in
+/// the source.
+///
+/// Ignored entries are not descended into. They are protected in the destination, unless
+/// `delete_excluded` is set.
+async fn delete_extraneous<E, S>(
+    root: &Path,
+    path: &Path,
+    client: &mut S,
+    ignore: &Ignore,
+    delete_excluded: bool,
+) -> anyhow::Result<()>
+where
+    E: std::error::Error + Sync + Send + 'static,
+    S: Service<proto::TransferRequest, Response = proto::TransferResponse, Error = E>,
+{
+    let mut dirs = vec![path.strip_prefix(root)?.to_path_buf()];
+    let mut dirs_to_remove = Vec::new();
+
+    while let Some(dir) = dirs.pop() {
+        let entries = match list_dir(client, &dir).await {
+            Ok(Ok(entries)) => entries,
+            Ok(Err(e)) => return Err(e), // fatal error
+            Err(e) => {
+                warn!(path = %dir.display(), reason = %e, "failed to list directory");
+                continue;
+            }
+        };
+
+        // entries are only deleted once all of the directory could be checked, so that entries
+        // which could not be read in the source are not mistaken for extraneous ones
+        let mut extraneous = Vec::new();
+        let mut missing_dirs = Vec::new();
+        let mut failed = false;
+        for entry in entries {
+            let is_dir = entry.file_type == proto::FileType::Dir;
+            let source_path = root.join(&entry.path);
+            if ignore.should_skip(&source_path, is_dir) {
+                if delete_excluded {
+                    extraneous.push((entry.path, is_dir, true));
+                } else {
+                    debug!(path = %entry.path.display(), "keeping ignored");
+                }
+                continue;
+            }
+
+            let exists = match fs::symlink_metadata(&source_path) {
+                Ok(_) => true,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+                Err(e) => {
+                    warn!(path = %source_path.display(), reason = %e, "failed to check source");
+                    failed = true;
+                    true
+                }
+            };
+            if is_dir {
+                // extraneous directories are removed after their children
+                dirs.push(entry.path.clone());
+                if !exists {
+                    missing_dirs.push(entry.path);
+                }
+            } else if !exists {
+                extraneous.push((entry.path, false, false));
+            }
+        }
+        if failed {
+            warn!(path = %dir.display(), "not deleting in directory after errors");
+            continue;
+        }
+
+        for (path, is_dir, ignored) in extraneous {
+            if ignored {
+                info!(path = %path.display(), "delete ignored");
+            } else {
+                info!(path = %path.display(), "delete file");
+            }
+            match handle_event_remove(client, root, &root.join(path), is_dir, is_dir).await {
+                Ok(Ok(())) => (),
+                Ok(e) => return e, // fatal error
+                Err(e) => warn!(reason = %e, "delete failed"),
+            }
+        }
+        dirs_to_remove.extend(missing_dirs);
+    }
+
+    for path in dirs_to_remove.into_iter().rev() {
+        info!(path = %path.display(), "delete dir");
+        match handle_event_remove(client, root, &root.join(path), true, false).await {
+            Ok(Ok(())) => (),
+            Ok(e) => return e, // fatal error
+            Err(e) => warn!(reason = %e, "delete failed"),
+        }
+    }
+    Ok(())
+}
+
+/// Lists a directory in the destination, fetching one page of entries after another.
+async fn list_dir<E, S>(
+    client: &mut S,
+    path: &Path,
+) -> anyhow::Result<anyhow::Result<Vec<proto::ListEntry>>>
+where
+    E: std::error::Error + Sync + Send + 'static,
+    S: Service<proto::TransferRequest, Response = proto::TransferResponse, Error = E>,
+{
+    let mut entries: Vec<proto::ListEntry> = Vec::new();
+    loop {
+        let req = proto::TransferRequest {
+            id: Uuid::new_v4(),
+            path: path.into(),
+            file_type: proto::FileType::Dir,
+            kind: proto::TransferRequestKind::List {
+                after: entries.last().map(|entry| entry.path.clone()),
+            },
+            transfer: None,
+            link_target: None,
+            metadata: None,
+        };
+
+        let resp = match send(client, req).await {
+            Ok(resp) => resp,
+            Err(e) => return Ok(Err(e.into())),
+        };
+
+        match resp.kind {
+            proto::TransferResponseKind::Listing {
+                entries: page,
+                more,
+            } => {
+                entries.extend(page);
+                if !more {
+                    return Ok(Ok(entries));
+                }
+            }
+            proto::TransferResponseKind::CantHandle { reason } => {
+                bail!("handler failed: {}", reason);
+            }
+            proto::TransferResponseKind::InvalidPath { path, violation } => {
+                bail!("handler refused {}: {}", path.display(), violation);
+            }
+            kind => return Ok(Err(anyhow!("protocol violation: got {:?}", kind))),
+        }
+    }
+}
+
 /// Handles a directory entry via a client conforming the transfer procotol.
 ///
 /// Outer result is the result of handling the entry. It is non-fatal and can be converted into a
@@ -271,14 +1149,14 @@ where
 async fn handle_entry<S, E>(
     client: &mut S,
     root: &Path,
-    entry: &DirEntry,
+    path: &Path,
+    session: &Session,
 ) -> anyhow::Result<anyhow::Result<()>>
 where
     E: std::error::Error + Sync + Send + 'static,
     S: Service<proto::TransferRequest, Response = proto::TransferResponse, Error = E>,
 {
-    let path = entry.path();
-    let metadata = entry.metadata()?;
+    let metadata = fs::symlink_metadata(path)?;
     let file_type = proto::FileType::from_fs(metadata.file_type())
         .ok_or_else(|| anyhow!("unknown file type"))?;
     match file_type {
@@ -288,10 +1166,54 @@ where
         }
         proto::FileType::File => {
             info!(path = %path.display(), "transfer file");
-            check_file(client, root, path).await
+            check_file(client, root, path, session).await
+        }
+        proto::FileType::Symlink => {
+            info!(path = %path.display(), "transfer symlink");
+            check_symlink(client, root, path, session).await
+        }
+    }
+}
+
+/// Transfers a path which appeared in the tree together with all its entries.
+///
+/// Entries of a directory moved in from outside of the tree are not reported by the watcher.
+/// Entries in `created` are skipped, since they are transferred by their own events.
+async fn check_tree<S, E>(
+    client: &mut S,
+    root: &Path,
+    path: &Path,
+    ignore: &Ignore,
+    session: &Session,
+    created: &HashSet<PathBuf>,
+) -> anyhow::Result<anyhow::Result<()>>
+where
+    E: std::error::Error + Sync + Send + 'static,
+    S: Service<proto::TransferRequest, Response = proto::TransferResponse, Error = E>,
+{
+    let mut paths = vec![path.to_path_buf()];
+    while let Some(path) = paths.pop() {
+        match handle_entry(client, root, &path, session).await {
+            Ok(Ok(())) => (),
+            Ok(e) => return Ok(e), // fatal error
+            Err(e) => {
+                warn!(path = %path.display(), reason = %e, "skipping");
+                continue;
+            }
+        }
+        if !fs::symlink_metadata(&path)?.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(&path)? {
+            let entry = entry?;
+            let is_dir = entry.file_type()?.is_dir();
+            let path = entry.path();
+            if !ignore.should_skip(&path, is_dir) && !created.contains(&path) {
+                paths.push(path);
+            }
         }
-        proto::FileType::Symlink => bail!("symlinks are not supported"),
     }
+    Ok(Ok(()))
 }
 
 async fn check_dir<S, E>(
@@ -310,6 +1232,37 @@ where
         file_type: proto::FileType::Dir,
         kind: proto::TransferRequestKind::Check,
         transfer: None,
+        link_target: None,
+        metadata: None,
+    };
+
+    send_request(client, req).await
+}
+
+async fn check_symlink<S, E>(
+    client: &mut S,
+    root: &Path,
+    path: &Path,
+    session: &Session,
+) -> anyhow::Result<anyhow::Result<()>>
+where
+    E: std::error::Error + Sync + Send + 'static,
+    S: Service<proto::TransferRequest, Response = proto::TransferResponse, Error = E>,
+{
+    if !session.capabilities.contains(proto::Capabilities::SYMLINKS) {
+        bail!("symlinks are not supported by the handler");
+    }
+    let target = fs::read_link(path)?;
+
+    let relative_path = path.strip_prefix(root)?;
+    let req = proto::TransferRequest {
+        id: Uuid::new_v4(),
+        path: relative_path.into(),
+        file_type: proto::FileType::Symlink,
+        kind: proto::TransferRequestKind::Check,
+        transfer: None,
+        link_target: Some(target),
+        metadata: None,
     };
 
     send_request(client, req).await
@@ -319,12 +1272,22 @@ async fn check_file<S, E>(
     client: &mut S,
     root: &Path,
     path: &Path,
+    session: &Session,
 ) -> anyhow::Result<anyhow::Result<()>>
 where
     E: std::error::Error + Sync + Send + 'static,
     S: Service<proto::TransferRequest, Response = proto::TransferResponse, Error = E>,
 {
-    let (mmap, shasum) = mmap_with_shasum(path)?;
+    // empty files can't be memory mapped and are compared by the shasum of no data
+    let file = if is_empty_file(path)? {
+        None
+    } else {
+        Some(MappedFile::open(path, session)?)
+    };
+    let (shasum, metadata) = match &file {
+        Some(file) => (file.shasum, file.metadata),
+        None => (shasum_bytes([]), session.metadata(&fs::metadata(path)?)),
+    };
 
     let relative_path = path.strip_prefix(root)?;
 
@@ -334,6 +1297,9 @@ where
         shasum,
         file_size: None,
         data_size: None,
+        offset: 0,
+        compressed: false,
+        last: false,
     };
     let req = proto::TransferRequest {
         id: Uuid::new_v4(),
@@ -341,6 +1307,8 @@ where
         file_type: proto::FileType::File,
         kind: proto::TransferRequestKind::Check,
         transfer: Some(transfer),
+        link_target: None,
+        metadata,
     };
 
     let resp = match send(client, req).await {
@@ -348,55 +1316,171 @@ where
         Err(e) => return Ok(Err(e.into())),
     };
 
-    match resp.kind {
-        proto::TransferResponseKind::Ok => Ok(Ok(())),
-        proto::TransferResponseKind::Different { signature } => {
-            transfer_delta_with_mmap(client, root, path, mmap, shasum, signature).await
+    match (resp.kind, file) {
+        (proto::TransferResponseKind::Ok, _) => Ok(Ok(())),
+        // an empty file replaces the previous contents as a whole
+        (
+            proto::TransferResponseKind::Different { .. }
+            | proto::TransferResponseKind::NeedContents,
+            None,
+        ) => transfer_empty(client, root, path, session).await,
+        (
+            proto::TransferResponseKind::Different {
+                signature,
+                block_size,
+                signature_size,
+            },
+            Some(file),
+        ) => {
+            debug!(path = %path.display(), block_size, signature_size, "different");
+            let signature =
+                match fetch_signature(client, relative_path, signature, signature_size).await? {
+                    Ok(signature) => signature,
+                    Err(e) => return Ok(Err(e)),
+                };
+            transfer_delta_with_mmap(client, root, path, file, signature, session).await
         }
-        proto::TransferResponseKind::NeedContents => {
-            transfer_contents_with_mmap(client, root, path, mmap, shasum).await
+        (proto::TransferResponseKind::NeedContents, Some(file)) => {
+            transfer_contents_with_mmap(client, root, path, file, session).await
         }
-        proto::TransferResponseKind::CantHandle { reason } => {
+        (proto::TransferResponseKind::CantHandle { reason }, _) => {
             bail!("handler failed: {}", reason);
         }
+        (proto::TransferResponseKind::InvalidPath { path, violation }, _) => {
+            bail!("handler refused {}: {}", path.display(), violation);
+        }
+        (kind, _) => Ok(Err(anyhow!("protocol violation: got {:?}", kind))),
     }
 }
 
+async fn transfer_metadata<S, E>(
+    client: &mut S,
+    root: &Path,
+    path: &Path,
+    session: &Session,
+) -> anyhow::Result<anyhow::Result<()>>
+where
+    E: std::error::Error + Sync + Send + 'static,
+    S: Service<proto::TransferRequest, Response = proto::TransferResponse, Error = E>,
+{
+    let fs_metadata = fs::symlink_metadata(path)?;
+    let file_type = proto::FileType::from_fs(fs_metadata.file_type())
+        .ok_or_else(|| anyhow!("unknown file type"))?;
+    let metadata = match session.metadata(&fs_metadata) {
+        // permissions and times of symlinks themselves are not transferred
+        Some(metadata) if file_type != proto::FileType::Symlink => metadata,
+        _ => return Ok(Ok(())),
+    };
+
+    let relative_path = path.strip_prefix(root)?;
+    let req = proto::TransferRequest {
+        id: Uuid::new_v4(),
+        path: relative_path.into(),
+        file_type,
+        kind: proto::TransferRequestKind::Metadata,
+        transfer: None,
+        link_target: None,
+        metadata: Some(metadata),
+    };
+
+    send_request(client, req).await
+}
+
 async fn transfer_contents<S, E>(
     client: &mut S,
     root: &Path,
     path: &Path,
+    session: &Session,
+) -> anyhow::Result<anyhow::Result<()>>
+where
+    E: std::error::Error + Sync + Send + 'static,
+    S: Service<proto::TransferRequest, Response = proto::TransferResponse, Error = E>,
+{
+    if is_empty_file(path)? {
+        return transfer_empty(client, root, path, session).await;
+    }
+    let file = MappedFile::open(path, session)?;
+    transfer_contents_with_mmap(client, root, path, file, session).await
+}
+
+/// Creates or truncates the file on the handler side.
+///
+/// Empty files can't be memory mapped and have no chunks to transfer, so they are sent as a single
+/// contents request without data.
+async fn transfer_empty<S, E>(
+    client: &mut S,
+    root: &Path,
+    path: &Path,
+    session: &Session,
 ) -> anyhow::Result<anyhow::Result<()>>
 where
     E: std::error::Error + Sync + Send + 'static,
     S: Service<proto::TransferRequest, Response = proto::TransferResponse, Error = E>,
 {
-    let (mmap, shasum) = mmap_with_shasum(path)?;
-    transfer_contents_with_mmap(client, root, path, mmap, shasum).await
+    let metadata = session.metadata(&fs::metadata(path)?);
+    let relative_path = path.strip_prefix(root)?;
+    let transfer = proto::Transfer {
+        data: Vec::new(),
+        kind: proto::TransferKind::Empty,
+        shasum: shasum_bytes([]),
+        file_size: Some(0),
+        data_size: Some(0),
+        offset: 0,
+        compressed: false,
+        last: false,
+    };
+    let req = proto::TransferRequest {
+        id: Uuid::new_v4(),
+        path: relative_path.into(),
+        file_type: proto::FileType::File,
+        kind: proto::TransferRequestKind::Contents,
+        transfer: Some(transfer),
+        link_target: None,
+        metadata,
+    };
+
+    send_request(client, req).await
 }
 
 async fn transfer_contents_with_mmap<S, E>(
     client: &mut S,
     root: &Path,
     path: &Path,
-    mmap: Mmap,
-    shasum: [u8; 32],
+    file: MappedFile,
+    session: &Session,
 ) -> anyhow::Result<anyhow::Result<()>>
 where
     E: std::error::Error + Sync + Send + 'static,
     S: Service<proto::TransferRequest, Response = proto::TransferResponse, Error = E>,
 {
     let relative_path = path.strip_prefix(root)?;
+    let file_size = file.mmap.len();
+
+    let offset = if session.capabilities.contains(proto::Capabilities::RESUME) {
+        match resume_offset(client, relative_path, &file).await? {
+            Ok(offset) => offset,
+            Err(e) => return Ok(Err(e)),
+        }
+    } else {
+        0
+    };
+    if offset > 0 {
+        info!(path = %path.display(), offset, file_size, "resuming transfer");
+    }
 
-    for (n, chunk) in mmap.chunks(FILE_CHUNK_SIZE).enumerate() {
+    let remaining = file.mmap[offset..].chunks(FILE_CHUNK_SIZE);
+    for (n, chunk) in remaining.enumerate() {
         debug!(path = %path.display(), chunk = n, "transfer chunk");
-        let file_size = mmap.len();
+        let (data, compressed) = session.compress(chunk)?;
         let transfer = proto::Transfer {
-            data: chunk.to_vec(),
+            data,
             kind: proto::TransferKind::Contents,
-            shasum,
+            shasum: file.shasum,
             file_size: Some(file_size),
             data_size: Some(file_size),
+            offset: (offset + n * FILE_CHUNK_SIZE) as u64,
+            compressed,
+            last: false,
         };
         let req = proto::TransferRequest {
             id: Uuid::new_v4(),
@@ -404,6 +1488,8 @@ where
             file_type: proto::FileType::File,
             kind: proto::TransferRequestKind::Contents,
             transfer: Some(transfer),
+            link_target: None,
+            metadata: file.metadata,
         };
 
         if let Err(e) = send_request(client, req).await? {
@@ -414,44 +1500,160 @@ where
     Ok(Ok(()))
 }
 
+/// Asks the handler how many bytes of the file it already received in an earlier transfer.
+async fn resume_offset<S, E>(
+    client: &mut S,
+    relative_path: &Path,
+    file: &MappedFile,
+) -> anyhow::Result<anyhow::Result<usize>>
+where
+    E: std::error::Error + Sync + Send + 'static,
+    S: Service<proto::TransferRequest, Response = proto::TransferResponse, Error = E>,
+{
+    let file_size = file.mmap.len();
+    let transfer = proto::Transfer {
+        data: Vec::new(),
+        kind: proto::TransferKind::Contents,
+        shasum: file.shasum,
+        file_size: Some(file_size),
+        data_size: Some(file_size),
+        offset: 0,
+        compressed: false,
+        last: false,
+    };
+    let req = proto::TransferRequest {
+        id: Uuid::new_v4(),
+        path: relative_path.into(),
+        file_type: proto::FileType::File,
+        kind: proto::TransferRequestKind::Resume,
+        transfer: Some(transfer),
+        link_target: None,
+        metadata: None,
+    };
+
+    let resp = match send(client, req).await {
+        Ok(resp) => resp,
+        Err(e) => return Ok(Err(e.into())),
+    };
+
+    match resp.kind {
+        proto::TransferResponseKind::ResumeAt { offset } if offset < file_size as u64 => {
+            Ok(Ok(offset as usize))
+        }
+        // the last chunk finishes the file, so it can't be complete yet
+        proto::TransferResponseKind::ResumeAt { .. } => Ok(Ok(0)),
+        proto::TransferResponseKind::CantHandle { reason } => {
+            bail!("handler failed: {}", reason);
+        }
+        proto::TransferResponseKind::InvalidPath { path, violation } => {
+            bail!("handler refused {}: {}", path.display(), violation);
+        }
+        kind => Ok(Err(anyhow!("protocol violation: got {:?}", kind))),
+    }
+}
+
+/// Fetches the chunks of a signature which did not fit into the `Different` response.
+async fn fetch_signature<S, E>(
+    client: &mut S,
+    relative_path: &Path,
+    mut signature: Vec<u8>,
+    signature_size: u64,
+) -> anyhow::Result<anyhow::Result<Vec<u8>>>
+where
+    E: std::error::Error + Sync + Send + 'static,
+    S: Service<proto::TransferRequest, Response = proto::TransferResponse, Error = E>,
+{
+    while (signature.len() as u64) < signature_size {
+        let req = proto::TransferRequest {
+            id: Uuid::new_v4(),
+            path: relative_path.into(),
+            file_type: proto::FileType::File,
+            kind: proto::TransferRequestKind::Signature {
+                offset: signature.len() as u64,
+            },
+            transfer: None,
+            link_target: None,
+            metadata: None,
+        };
+
+        let resp = match send(client, req).await {
+            Ok(resp) => resp,
+            Err(e) => return Ok(Err(e.into())),
+        };
+
+        match resp.kind {
+            proto::TransferResponseKind::SignatureChunk { data } if !data.is_empty() => {
+                signature.extend(data)
+            }
+            proto::TransferResponseKind::CantHandle { reason } => {
+                bail!("handler failed: {}", reason);
+            }
+            proto::TransferResponseKind::InvalidPath { path, violation } => {
+                bail!("handler refused {}: {}", path.display(), violation);
+            }
+            kind => return Ok(Err(anyhow!("protocol violation: got {:?}", kind))),
+        }
+    }
+    Ok(Ok(signature))
+}
+
 async fn transfer_delta_with_mmap<S, E>(
     client: &mut S,
     root: &Path,
     path: &Path,
-    mmap: Mmap,
-    shasum: [u8; 32],
+    file: MappedFile,
     signature: Vec<u8>,
+    session: &Session,
 ) -> anyhow::Result<anyhow::Result<()>>
 where
     E: std::error::Error + Sync + Send + 'static,
     S: Service<proto::TransferRequest, Response = proto::TransferResponse, Error = E>,
 {
-    let sig = Signature::deserialize(&signature)?;
-    let mut delta = Vec::new();
-    diff(&sig.index(), &mmap, &mut delta)?;
-
     let relative_path = path.strip_prefix(root)?;
+    let file_size = file.mmap.len();
+    let shasum = file.shasum;
+    let metadata = file.metadata;
 
-    let chunks = delta.chunks(FILE_CHUNK_SIZE);
-    let num_chunks = chunks.len();
+    // the delta is generated in the background and sent chunk by chunk, so it is never kept in
+    // memory as a whole
+    let (tx, mut rx) = mpsc::channel(1);
+    let generator = tokio::task::spawn_blocking(move || {
+        let res = (|| -> anyhow::Result<()> {
+            let sig = Signature::deserialize(&signature)?;
+            let mut out = ChunkWriter::new(tx, FILE_CHUNK_SIZE);
+            diff(&sig.index(), &file.mmap, &mut out)?;
+            out.finish()?;
+            Ok(())
+        })();
+        (file, res)
+    });
 
+    let mut offset = 0;
     let mut needs_contents = false;
 
-    for (n, chunk) in chunks.enumerate() {
+    let mut n = 0;
+    while let Some((chunk, last)) = rx.recv().await {
         debug!(path = %path.display(), chunk = n, "transfer chunk");
+        let (data, compressed) = session.compress(&chunk)?;
         let transfer = proto::Transfer {
             kind: proto::TransferKind::Delta,
-            data: chunk.to_vec(),
+            data,
             shasum,
-            file_size: Some(mmap.len()),
-            data_size: Some(delta.len()),
+            file_size: Some(file_size),
+            data_size: None,
+            offset,
+            compressed,
+            last,
         };
+        offset += chunk.len() as u64;
         let req = proto::TransferRequest {
             id: Uuid::new_v4(),
             path: relative_path.into(),
             file_type: proto::FileType::File,
             kind: proto::TransferRequestKind::Delta,
             transfer: Some(transfer),
+            link_target: None,
+            metadata,
         };
 
         match send(client, req).await {
@@ -462,7 +1664,7 @@ where
             Ok(proto::TransferResponse {
                 kind: proto::TransferResponseKind::NeedContents,
                 ..
-            }) if n + 1 == num_chunks => {
+            }) if last => {
                 // apply delta failed
                 needs_contents = true;
             }
@@ -470,25 +1672,36 @@ where
                 kind: proto::TransferResponseKind::CantHandle { reason },
                 ..
             }) => bail!("handler failed: {}", reason),
+            Ok(proto::TransferResponse {
+                kind: proto::TransferResponseKind::InvalidPath { path, violation },
+                ..
+            }) => bail!("handler refused {}: {}", path.display(), violation),
             Ok(resp) => {
                 return Ok(Err(anyhow!(
-                    "protocol violation: got {:?} for chunk {}/{}",
+                    "protocol violation: got {:?} for chunk {}",
                     resp.kind,
-                    n,
-                    num_chunks
+                    n
                 )))
             }
             Err(e) => return Ok(Err(e.into())),
         }
+        n += 1;
     }
 
+    let (file, res) = generator.await?;
+    res.context("failed to generate delta")?;
+
     if needs_contents {
-        transfer_contents_with_mmap(client, root, path, mmap, shasum).await
+        transfer_contents_with_mmap(client, root, path, file, session).await
     } else {
         Ok(Ok(()))
     }
 }
 
+fn is_empty_file(path: &Path) -> io::Result<bool> {
+    Ok(fs::metadata(path)?.len() == 0)
+}
+
 /// Sends requests and waits for success response.
 ///
 /// If the client fails, or protocol is violated, returns an inner error. When client received the
@@ -510,6 +1723,10 @@ where
             kind: proto::TransferResponseKind::CantHandle { reason },
             ..
         }) => bail!("handler failed: {}", reason),
+        Ok(proto::TransferResponse {
+            kind: proto::TransferResponseKind::InvalidPath { path, violation },
+            ..
+        }) => bail!("handler refused {}: {}", path.display(), violation),
         Ok(resp) => Err(anyhow!("protocol violation: got {:?}", resp.kind)),
         Err(e) => Err(e.into()),
     })
@@ -528,3 +1745,92 @@ where
     // trace!(?resp, "received");
     resp
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removed_dirs_cover_their_children() {
+        let removed = Path::new("/nonexistent/syncd/removed");
+        let mut removed_dirs = RemovedDirs::default();
+        removed_dirs.insert(removed.join("child"));
+        removed_dirs.insert(removed.to_path_buf());
+        assert_eq!(removed_dirs.0, [removed]);
+
+        assert!(removed_dirs.covers(&removed.join("child/file")));
+        assert!(!removed_dirs.covers(removed));
+        assert!(!removed_dirs.covers(Path::new("/nonexistent/syncd/removed2")));
+        removed_dirs.clear();
+        assert!(!removed_dirs.covers(&removed.join("child/file")));
+    }
+
+    #[test]
+    fn recreated_dirs_dont_cover_their_children() {
+        let recreated = std::env::temp_dir();
+        let mut removed_dirs = RemovedDirs::default();
+        removed_dirs.insert(recreated.clone());
+        assert!(!removed_dirs.covers(&recreated.join("file")));
+    }
+
+    #[test]
+    fn renames_are_paired_by_tracker() {
+        let mut renames = RenameTracker::default();
+        renames.push_from(Some(1), PathBuf::from("a"));
+        renames.push_from(Some(2), PathBuf::from("b"));
+        assert_eq!(renames.pair_to(Some(2)), Some(PathBuf::from("b")));
+        assert_eq!(renames.pair_to(Some(3)), None);
+        assert_eq!(renames.pair_to(Some(1)), Some(PathBuf::from("a")));
+        assert_eq!(renames.next_deadline(), None);
+
+        // the `Both` event of paired halves is skipped once
+        assert!(renames.take_paired(Some(1)));
+        assert!(!renames.take_paired(Some(1)));
+        assert!(!renames.take_paired(None));
+    }
+
+    #[test]
+    fn renames_without_trackers_pair_oldest() {
+        let mut renames = RenameTracker::default();
+        renames.push_from(None, PathBuf::from("a"));
+        renames.push_from(None, PathBuf::from("b"));
+        assert_eq!(renames.pair_to(None), Some(PathBuf::from("a")));
+        assert_eq!(renames.pair_to(None), Some(PathBuf::from("b")));
+        assert_eq!(renames.pair_to(None), None);
+    }
+
+    #[test]
+    fn both_events_supersede_pending_halves() {
+        let mut renames = RenameTracker::default();
+        renames.push_from(Some(1), PathBuf::from("a"));
+        assert!(!renames.take_paired(Some(1)));
+        assert_eq!(renames.pair_to(Some(1)), None);
+    }
+
+    #[test]
+    fn unpaired_renames_expire_as_removals() {
+        let mut renames = RenameTracker::default();
+        renames.push_from(Some(1), PathBuf::from("a"));
+        renames.push_from(Some(2), PathBuf::from("b"));
+        assert!(!renames.is_expired());
+
+        renames.pending[0].deadline = Instant::now();
+        assert!(renames.is_expired());
+        let event = renames.pop_expired().unwrap();
+        assert_eq!(event.kind, EventKind::Remove(RemoveKind::Any));
+        assert_eq!(event.paths, [PathBuf::from("a")]);
+        assert!(!renames.is_expired());
+    }
+
+    #[test]
+    fn paired_trackers_are_bounded() {
+        let mut renames = RenameTracker::default();
+        for tracker in 0..RenameTracker::MAX_PAIRED + 1 {
+            renames.push_from(Some(tracker), PathBuf::from("a"));
+            renames.pair_to(Some(tracker));
+        }
+        assert_eq!(renames.paired.len(), RenameTracker::MAX_PAIRED);
+        assert!(!renames.take_paired(Some(0)));
+        assert!(renames.take_paired(Some(RenameTracker::MAX_PAIRED)));
+    }
+}
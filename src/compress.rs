@@ -0,0 +1,118 @@
+//! Optional zstd compression of transferred chunks.
+
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Compressed data has to save at least 1/32 of the input, otherwise it is sent raw.
+const MIN_SAVINGS_DIVISOR: usize = 32;
+
+/// Clamps a compression level to the levels supported by zstd.
+pub fn clamp_level(level: i32) -> i32 {
+    let range = zstd::compression_level_range();
+    level.clamp(*range.start(), *range.end())
+}
+
+/// Compresses a chunk of data.
+///
+/// Returns `None` if the data is incompressible and should be sent raw.
+pub fn compress(data: &[u8], level: i32) -> io::Result<Option<Vec<u8>>> {
+    let compressed = zstd::encode_all(data, level)?;
+    if compressed.len() + data.len() / MIN_SAVINGS_DIVISOR < data.len() {
+        Ok(Some(compressed))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Decompresses a chunk of data, which decompresses to at most `max_size` bytes.
+pub fn decompress(data: &[u8], max_size: usize) -> io::Result<Vec<u8>> {
+    let decoder = zstd::Decoder::new(data)?;
+    let mut decompressed = Vec::new();
+    decoder
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut decompressed)?;
+    if decompressed.len() > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("decompressed chunk exceeds {} bytes", max_size),
+        ));
+    }
+    Ok(decompressed)
+}
+
+/// Counts the bytes before and after compression.
+#[derive(Debug, Default)]
+pub struct Stats {
+    raw_bytes: AtomicU64,
+    sent_bytes: AtomicU64,
+}
+
+impl Stats {
+    pub fn record(&self, raw_bytes: usize, sent_bytes: usize) {
+        self.raw_bytes
+            .fetch_add(raw_bytes as u64, Ordering::Relaxed);
+        self.sent_bytes
+            .fetch_add(sent_bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn raw_bytes(&self) -> u64 {
+        self.raw_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn sent_bytes(&self) -> u64 {
+        self.sent_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn saved_bytes(&self) -> u64 {
+        self.raw_bytes().saturating_sub(self.sent_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_round_trip() {
+        let data = b"abcd".repeat(1024);
+        let compressed = compress(&data, 3).unwrap().unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompress(&compressed, data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn incompressible_chunks_are_sent_raw() {
+        let data: Vec<u8> = (0..128u32)
+            .flat_map(|i| crate::shasum_bytes(i.to_le_bytes()))
+            .collect();
+        assert_eq!(compress(&data, 3).unwrap(), None);
+        assert_eq!(compress(b"", 3).unwrap(), None);
+    }
+
+    #[test]
+    fn oversized_chunks_are_rejected() {
+        let data = vec![0; 4096];
+        let compressed = compress(&data, 3).unwrap().unwrap();
+        let e = decompress(&compressed, data.len() - 1).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(decompress(b"not zstd", 4096).is_err());
+    }
+
+    #[test]
+    fn levels_are_clamped() {
+        let range = zstd::compression_level_range();
+        assert_eq!(clamp_level(1000), *range.end());
+        assert_eq!(clamp_level(i32::MIN), *range.start());
+        assert_eq!(clamp_level(3), 3);
+    }
+
+    #[test]
+    fn stats_count_saved_bytes() {
+        let stats = Stats::default();
+        stats.record(100, 40);
+        stats.record(10, 10);
+        assert_eq!(stats.raw_bytes(), 110);
+        assert_eq!(stats.sent_bytes(), 50);
+        assert_eq!(stats.saved_bytes(), 60);
+    }
+}
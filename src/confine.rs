@@ -0,0 +1,122 @@
+//! Confinement of request paths to the root of the transfer handler.
+
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::proto::PathViolation;
+
+/// Resolves a path relative to `root` and checks that it stays inside of it.
+///
+/// `root` has to be canonical. The path is rejected if it is absolute, contains `..`, or if one of
+/// its existing ancestors is a symlink pointing outside of the root. If `follow_last` is set, the
+/// last component is checked as well, which is needed when writing through it.
+pub fn confine(root: &Path, path: &Path, follow_last: bool) -> Result<PathBuf, PathViolation> {
+    let mut relative_path = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => relative_path.push(name),
+            Component::CurDir => (),
+            Component::ParentDir => return Err(PathViolation::ParentDir),
+            Component::RootDir | Component::Prefix(_) => return Err(PathViolation::Absolute),
+        }
+    }
+
+    let mut ancestor = root.to_path_buf();
+    let mut components = relative_path.components().peekable();
+    while let Some(component) = components.next() {
+        ancestor.push(component);
+        if components.peek().is_none() && !follow_last {
+            break;
+        }
+        match fs::symlink_metadata(&ancestor) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                // dangling symlinks are rejected as well, since writing through them would create
+                // the target
+                match ancestor.canonicalize() {
+                    Ok(target) if target.starts_with(root) => (),
+                    _ => return Err(PathViolation::SymlinkOutsideRoot),
+                }
+            }
+            Ok(_) => (),
+            // nothing to follow below a non-existing path
+            Err(_) => break,
+        }
+    }
+    Ok(root.join(relative_path))
+}
+
+/// Returns true if the path refers to the root itself.
+pub fn is_root(path: &Path) -> bool {
+    path.components().all(|c| c == Component::CurDir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn relative_paths_are_joined() {
+        let root = TempDir::new();
+        let root = root.path();
+        assert_eq!(
+            confine(root, Path::new("a/./b"), true),
+            Ok(root.join("a/b"))
+        );
+        assert_eq!(confine(root, Path::new("."), true), Ok(root.to_path_buf()));
+    }
+
+    #[test]
+    fn escaping_paths_are_rejected() {
+        let root = TempDir::new();
+        let root = root.path();
+        assert_eq!(
+            confine(root, Path::new("a/../../b"), true),
+            Err(PathViolation::ParentDir)
+        );
+        assert_eq!(
+            confine(root, Path::new("/etc/passwd"), true),
+            Err(PathViolation::Absolute)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_outside_of_root_are_rejected() {
+        use std::os::unix::fs::symlink;
+
+        let root = TempDir::new();
+        let outside = TempDir::new();
+        symlink(outside.path(), root.path().join("out")).unwrap();
+        symlink("missing", root.path().join("dangling")).unwrap();
+        fs::create_dir(root.path().join("dir")).unwrap();
+        symlink(root.path().join("dir"), root.path().join("in")).unwrap();
+        let root = root.path();
+
+        assert_eq!(
+            confine(root, Path::new("out/file"), false),
+            Err(PathViolation::SymlinkOutsideRoot)
+        );
+        assert_eq!(
+            confine(root, Path::new("out"), true),
+            Err(PathViolation::SymlinkOutsideRoot)
+        );
+        assert_eq!(
+            confine(root, Path::new("dangling"), true),
+            Err(PathViolation::SymlinkOutsideRoot)
+        );
+        // the link itself can be removed or replaced
+        assert_eq!(confine(root, Path::new("out"), false), Ok(root.join("out")));
+        assert_eq!(
+            confine(root, Path::new("in/file"), true),
+            Ok(root.join("in/file"))
+        );
+    }
+
+    #[test]
+    fn root_is_detected() {
+        assert!(is_root(Path::new("")));
+        assert!(is_root(Path::new("./.")));
+        assert!(!is_root(Path::new("a")));
+    }
+}
@@ -0,0 +1,441 @@
+//! Coalescing of file system events which arrive in quick succession.
+//!
+//! Events are collected until no new event arrived for the settle window. Events of the same path
+//! are merged, so that only the latest state is transferred, and a path created and removed within
+//! the window is not transferred at all. Renames and unknown events are kept in order and split the
+//! window into segments, since changes before and after them can't be merged. Pending changes of a
+//! renamed path follow it to the rename target.
+
+use std::collections::HashMap;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use notify::event::{
+    CreateKind, DataChange, Flag, MetadataKind, ModifyKind, RemoveKind, RenameMode,
+};
+use notify::{Event, EventKind};
+use tokio::time::Instant;
+
+/// A batch is processed at the latest after this many settle windows, even if events keep arriving
+const MAX_BATCH_AGE_FACTOR: u32 = 10;
+
+#[derive(Debug)]
+pub struct Debouncer {
+    settle: Duration,
+    /// coalesced changes and events kept in order; `None` for cancelled changes
+    pending: Vec<Option<Pending>>,
+    /// index of the change of a path in the current segment
+    paths: HashMap<PathBuf, usize>,
+    /// changes below the source of a rename reported as separate halves, by tracker, relative to
+    /// the source
+    moved: HashMap<usize, Vec<(PathBuf, Change)>>,
+    first_event: Option<Instant>,
+    last_event: Option<Instant>,
+}
+
+#[derive(Debug)]
+enum Pending {
+    Change(PathBuf, Change),
+    Event(Event),
+}
+
+#[derive(Debug)]
+struct Change {
+    /// path did not exist before the first event
+    created: bool,
+    /// previous entry was removed before the path was created again
+    replaced: Option<RemoveKind>,
+    state: State,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    Created(CreateKind),
+    Modified,
+    Metadata,
+    Removed(RemoveKind),
+}
+
+impl Debouncer {
+    pub fn new(settle: Duration) -> Self {
+        Self {
+            settle,
+            pending: Vec::new(),
+            paths: HashMap::new(),
+            moved: HashMap::new(),
+            first_event: None,
+            last_event: None,
+        }
+    }
+
+    pub fn push(&mut self, event: Event) {
+        let now = Instant::now();
+        self.first_event.get_or_insert(now);
+        self.last_event = Some(now);
+
+        let path = match event.paths.as_slice() {
+            [path] if event.flag() != Some(Flag::Rescan) => path.clone(),
+            _ => return self.push_barrier(event),
+        };
+        let state = match event.kind {
+            // opening and closing files is not transferred
+            EventKind::Access(_) => return,
+            EventKind::Create(kind) => State::Created(kind),
+            EventKind::Modify(ModifyKind::Data(_)) => State::Modified,
+            EventKind::Modify(ModifyKind::Metadata(_)) => State::Metadata,
+            EventKind::Remove(kind) => State::Removed(kind),
+            _ => return self.push_barrier(event),
+        };
+
+        let index = match self.paths.get(&path) {
+            Some(&index) => index,
+            None => {
+                self.paths.insert(path.clone(), self.pending.len());
+                let change = Change {
+                    created: matches!(state, State::Created(_)),
+                    replaced: None,
+                    state,
+                };
+                self.pending.push(Some(Pending::Change(path, change)));
+                return;
+            }
+        };
+        let change = match &mut self.pending[index] {
+            Some(Pending::Change(_, change)) => change,
+            _ => unreachable!("path index points to a change"),
+        };
+        match (change.state, state) {
+            (_, State::Removed(_)) if change.created => {
+                // created and removed within the window
+                self.pending[index] = None;
+                self.paths.remove(&path);
+            }
+            (State::Removed(kind), State::Created(_)) => {
+                change.replaced = Some(kind);
+                change.state = state;
+            }
+            // the contents of a created path are transferred as a whole
+            (State::Created(_), State::Modified | State::Metadata) => (),
+            // contents are transferred together with the metadata
+            (State::Modified, State::Metadata) => (),
+            (_, state) => change.state = state,
+        }
+    }
+
+    fn push_barrier(&mut self, event: Event) {
+        // the source of a rename doesn't exist anymore when the batch is drained, so its pending
+        // changes are applied to the target after the rename
+        let mut carried = Vec::new();
+        match (event.kind, event.paths.as_slice()) {
+            (EventKind::Modify(ModifyKind::Name(RenameMode::From)), [from]) => {
+                if let Some(tracker) = event.tracker() {
+                    let changes = self.take_changes(from);
+                    self.moved.insert(tracker, changes);
+                }
+            }
+            (EventKind::Modify(ModifyKind::Name(RenameMode::To)), [to]) => {
+                if let Some(changes) = event.tracker().and_then(|t| self.moved.remove(&t)) {
+                    carried = Self::move_changes(to, changes);
+                }
+            }
+            (EventKind::Modify(ModifyKind::Name(_)), [from, to]) => {
+                let changes = self.take_changes(from);
+                carried = Self::move_changes(to, changes);
+            }
+            _ => (),
+        }
+
+        self.paths.clear();
+        self.pending.push(Some(Pending::Event(event)));
+        for (path, change) in carried {
+            self.paths.insert(path.clone(), self.pending.len());
+            self.pending.push(Some(Pending::Change(path, change)));
+        }
+    }
+
+    /// Cancels the changes of the current segment at or below `from` and returns them with paths
+    /// relative to `from`.
+    ///
+    /// Removals stay in place, since they apply before the rename. A renamed path created within
+    /// the window is transferred as a whole by the rename.
+    fn take_changes(&mut self, from: &Path) -> Vec<(PathBuf, Change)> {
+        let mut changes = Vec::new();
+        for (path, &index) in &self.paths {
+            let relative = match path.strip_prefix(from) {
+                Ok(relative) => relative.to_path_buf(),
+                Err(_) => continue,
+            };
+            let change = match &self.pending[index] {
+                Some(Pending::Change(_, change)) => change,
+                _ => continue,
+            };
+            if matches!(change.state, State::Removed(_))
+                || (change.created && relative.as_os_str().is_empty())
+            {
+                continue;
+            }
+            if let Some(Pending::Change(_, change)) = self.pending[index].take() {
+                changes.push((relative, change));
+            }
+        }
+        changes
+    }
+
+    /// Places changes taken from the source of a rename below its target.
+    fn move_changes(to: &Path, changes: Vec<(PathBuf, Change)>) -> Vec<(PathBuf, Change)> {
+        let mut moved: Vec<_> = changes
+            .into_iter()
+            .map(|(relative, change)| {
+                if relative.as_os_str().is_empty() {
+                    (to.to_path_buf(), change)
+                } else {
+                    (to.join(relative), change)
+                }
+            })
+            .collect();
+        moved.sort_by(|a, b| a.0.cmp(&b.0));
+        moved
+    }
+
+    /// Returns when the pending events are ready to be processed.
+    pub fn deadline(&self) -> Option<Instant> {
+        let first_event = self.first_event?;
+        let last_event = self.last_event?;
+        Some((last_event + self.settle).min(first_event + self.settle * MAX_BATCH_AGE_FACTOR))
+    }
+
+    /// Returns the coalesced events and starts a new batch.
+    pub fn drain(&mut self) -> Vec<Event> {
+        self.paths.clear();
+        self.moved.clear();
+        self.first_event = None;
+        self.last_event = None;
+
+        let mut events = Vec::new();
+        for pending in mem::take(&mut self.pending).into_iter().flatten() {
+            match pending {
+                Pending::Event(event) => events.push(event),
+                Pending::Change(path, change) => change.into_events(path, &mut events),
+            }
+        }
+        events
+    }
+}
+
+impl Change {
+    fn into_events(self, path: PathBuf, events: &mut Vec<Event>) {
+        let kind = match self.state {
+            State::Removed(kind) => {
+                let kind = self.replaced.unwrap_or(kind);
+                events.push(Event::new(EventKind::Remove(kind)).add_path(path));
+                return;
+            }
+            State::Created(kind) => EventKind::Create(kind),
+            State::Modified => EventKind::Modify(ModifyKind::Data(DataChange::Any)),
+            State::Metadata => EventKind::Modify(ModifyKind::Metadata(MetadataKind::Any)),
+        };
+        if path.symlink_metadata().is_err() {
+            // renamed or removed later, which is handled by the following events
+            return;
+        }
+        if let Some(replaced) = self.replaced {
+            events.push(Event::new(EventKind::Remove(replaced)).add_path(path.clone()));
+        }
+        events.push(Event::new(kind).add_path(path));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn event(kind: EventKind, path: &std::path::Path) -> Event {
+        Event::new(kind).add_path(path.to_path_buf())
+    }
+
+    fn debouncer() -> Debouncer {
+        Debouncer::new(Duration::from_millis(100))
+    }
+
+    const CREATE: EventKind = EventKind::Create(CreateKind::File);
+    const MODIFY: EventKind = EventKind::Modify(ModifyKind::Data(DataChange::Any));
+    const METADATA: EventKind = EventKind::Modify(ModifyKind::Metadata(MetadataKind::Any));
+    const REMOVE: EventKind = EventKind::Remove(RemoveKind::File);
+
+    fn rename_event(mode: RenameMode, paths: &[&PathBuf]) -> Event {
+        paths.iter().fold(
+            Event::new(EventKind::Modify(ModifyKind::Name(mode))).set_tracker(1),
+            |event, path| event.add_path(path.to_path_buf()),
+        )
+    }
+
+    #[test]
+    fn changes_of_a_path_are_merged() {
+        let dir = TempDir::new();
+        let (created, modified) = (dir.write("created", ""), dir.write("modified", ""));
+        let mut debouncer = debouncer();
+        for kind in [CREATE, MODIFY, METADATA] {
+            debouncer.push(event(kind, &created));
+        }
+        for kind in [METADATA, MODIFY, METADATA] {
+            debouncer.push(event(kind, &modified));
+        }
+        debouncer.push(event(
+            EventKind::Access(notify::event::AccessKind::Any),
+            &created,
+        ));
+        assert_eq!(
+            debouncer.drain(),
+            [event(CREATE, &created), event(MODIFY, &modified)]
+        );
+        assert!(debouncer.drain().is_empty());
+    }
+
+    #[test]
+    fn created_and_removed_paths_are_dropped() {
+        let dir = TempDir::new();
+        let path = dir.path().join("temp");
+        let mut debouncer = debouncer();
+        debouncer.push(event(CREATE, &path));
+        debouncer.push(event(MODIFY, &path));
+        debouncer.push(event(REMOVE, &path));
+        assert!(debouncer.drain().is_empty());
+    }
+
+    #[test]
+    fn replaced_paths_are_removed_first() {
+        let dir = TempDir::new();
+        let path = dir.write("replaced", "");
+        let mut debouncer = debouncer();
+        debouncer.push(event(REMOVE, &path));
+        debouncer.push(event(EventKind::Create(CreateKind::Folder), &path));
+        assert_eq!(
+            debouncer.drain(),
+            [
+                event(REMOVE, &path),
+                event(EventKind::Create(CreateKind::Folder), &path)
+            ]
+        );
+    }
+
+    #[test]
+    fn changes_of_missing_paths_are_dropped() {
+        let dir = TempDir::new();
+        let path = dir.path().join("missing");
+        let mut debouncer = debouncer();
+        debouncer.push(event(MODIFY, &path));
+        assert!(debouncer.drain().is_empty());
+        debouncer.push(event(REMOVE, &path));
+        assert_eq!(debouncer.drain(), [event(REMOVE, &path)]);
+    }
+
+    #[test]
+    fn renames_split_segments() {
+        let dir = TempDir::new();
+        let (a, b) = (dir.write("a", ""), dir.write("b", ""));
+        let rename = rename_event(RenameMode::Both, &[&a, &b]);
+        let mut debouncer = debouncer();
+        debouncer.push(event(MODIFY, &b));
+        debouncer.push(rename.clone());
+        debouncer.push(event(MODIFY, &a));
+        debouncer.push(event(METADATA, &b));
+        debouncer.push(event(MODIFY, &a));
+        assert_eq!(
+            debouncer.drain(),
+            [
+                event(MODIFY, &b),
+                rename,
+                event(MODIFY, &a),
+                event(METADATA, &b)
+            ]
+        );
+    }
+
+    #[test]
+    fn changes_follow_renamed_paths() {
+        let dir = TempDir::new();
+        let (a, b) = (dir.path().join("a"), dir.write("b", ""));
+        let rename = rename_event(RenameMode::Both, &[&a, &b]);
+        let mut debouncer = debouncer();
+        debouncer.push(event(MODIFY, &a));
+        debouncer.push(rename.clone());
+        assert_eq!(debouncer.drain(), [rename, event(MODIFY, &b)]);
+
+        // inotify reports the halves of a rename before the whole
+        let (from, to, both) = (
+            rename_event(RenameMode::From, &[&a]),
+            rename_event(RenameMode::To, &[&b]),
+            rename_event(RenameMode::Both, &[&a, &b]),
+        );
+        debouncer.push(event(METADATA, &a));
+        for event in [&from, &to, &both] {
+            debouncer.push(event.clone());
+        }
+        assert_eq!(debouncer.drain(), [from, to, event(METADATA, &b), both]);
+    }
+
+    #[test]
+    fn changes_follow_renamed_parents() {
+        let dir = TempDir::new();
+        let (old, new) = (dir.path().join("old"), dir.path().join("new"));
+        fs::create_dir(&new).unwrap();
+        let (modified, created) = (dir.write("new/modified", ""), dir.write("new/created", ""));
+        let removed = old.join("removed");
+        let rename = rename_event(RenameMode::Both, &[&old, &new]);
+        let mut debouncer = debouncer();
+        debouncer.push(event(REMOVE, &removed));
+        debouncer.push(event(MODIFY, &old.join("modified")));
+        debouncer.push(event(CREATE, &old.join("created")));
+        debouncer.push(rename.clone());
+        assert_eq!(
+            debouncer.drain(),
+            [
+                event(REMOVE, &removed),
+                rename,
+                event(CREATE, &created),
+                event(MODIFY, &modified)
+            ]
+        );
+    }
+
+    #[test]
+    fn renamed_paths_created_within_the_window_are_transferred_by_the_rename() {
+        let dir = TempDir::new();
+        let (a, b) = (dir.path().join("a"), dir.write("b", ""));
+        let rename = rename_event(RenameMode::Both, &[&a, &b]);
+        let mut debouncer = debouncer();
+        debouncer.push(event(CREATE, &a));
+        debouncer.push(event(MODIFY, &a));
+        debouncer.push(rename.clone());
+        assert_eq!(debouncer.drain(), [rename]);
+    }
+
+    #[test]
+    fn deadline_follows_the_last_event() {
+        let dir = TempDir::new();
+        let path = dir.write("file", "");
+        let mut debouncer = debouncer();
+        assert_eq!(debouncer.deadline(), None);
+
+        let before = Instant::now();
+        debouncer.push(event(MODIFY, &path));
+        let deadline = debouncer.deadline().unwrap();
+        assert!(deadline >= before + debouncer.settle);
+        assert!(deadline <= Instant::now() + debouncer.settle);
+
+        // events arriving continuously don't postpone the batch forever
+        let first_event = before - debouncer.settle * 20;
+        debouncer.first_event = Some(first_event);
+        assert_eq!(
+            debouncer.deadline(),
+            Some(first_event + debouncer.settle * MAX_BATCH_AGE_FACTOR)
+        );
+
+        debouncer.drain();
+        assert_eq!(debouncer.deadline(), None);
+    }
+}
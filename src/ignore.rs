@@ -1,10 +1,18 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
-use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder, Glob};
+use ignore::WalkBuilder;
 use tracing::debug;
 
 use crate::pathutil;
 
+/// Ignore file only read by syncd
+pub const SYNCD_IGNORE: &str = ".syncdignore";
+
 #[derive(Debug)]
 pub struct IgnoreBuilder {
     root: PathBuf,
@@ -12,6 +20,10 @@ pub struct IgnoreBuilder {
     ignore_dot: bool,
     /// ignore hidden files
     hidden: bool,
+    /// globs excluded on the command line
+    excludes: Vec<String>,
+    /// globs included on the command line
+    includes: Vec<String>,
 }
 
 impl IgnoreBuilder {
@@ -21,23 +33,25 @@ impl IgnoreBuilder {
             debug!(error = %e, "global gitignore failed")
         }
 
-        let mut ignore_builder = GitignoreBuilder::new(&self.root);
-        if let Some(e) = ignore_builder.add(".gitignore") {
-            debug!(error = %e, ".gitignore failed");
+        let exclude = load_exclude(&self.root)?;
+
+        let mut overrides_builder = GitignoreBuilder::new(&self.root);
+        for glob in &self.excludes {
+            overrides_builder.add_line(None, glob)?;
         }
-        if self.ignore_dot {
-            if let Some(e) = ignore_builder.add(".ignore") {
-                debug!(error = %e, ".ignore failed");
-            }
+        // includes are added last, so that they take precedence over excludes
+        for glob in &self.includes {
+            overrides_builder.add_line(None, &format!("!{}", glob))?;
         }
-        ignore_builder
-            .add_line(None, ".git/**/*.lock")
-            .expect("invalid rule");
-        let local_ignore = ignore_builder.build()?;
+        let overrides = overrides_builder.build()?;
 
         Ok(Ignore {
+            root: self.root.clone(),
+            overrides,
             global_ignore,
-            local_ignore,
+            exclude: Mutex::new(exclude),
+            dirs: Default::default(),
+            ignore_dot: self.ignore_dot,
             ignore_hidden: !self.hidden,
         })
     }
@@ -51,56 +65,581 @@ impl IgnoreBuilder {
         self.hidden = value;
         self
     }
+
+    /// Skips paths matching the gitignore glob.
+    pub fn exclude(&mut self, glob: impl Into<String>) -> &mut Self {
+        self.excludes.push(glob.into());
+        self
+    }
+
+    /// Transfers paths matching the gitignore glob, even if they are excluded otherwise.
+    pub fn include(&mut self, glob: impl Into<String>) -> &mut Self {
+        self.includes.push(glob.into());
+        self
+    }
+}
+
+/// Ignore files read in each directory, by descending precedence
+const IGNORE_FILES: [&str; 3] = [SYNCD_IGNORE, ".ignore", ".gitignore"];
+
+fn exclude_path(root: &Path) -> PathBuf {
+    root.join(".git").join("info").join("exclude")
+}
+
+/// Loads `.git/info/exclude` together with the built-in rules.
+fn load_exclude(root: &Path) -> Result<Gitignore, ignore::Error> {
+    let mut builder = GitignoreBuilder::new(root);
+    let path = exclude_path(root);
+    if path.is_file() {
+        if let Some(e) = builder.add(&path) {
+            debug!(error = %e, ".git/info/exclude failed");
+        }
+    }
+    builder
+        .add_line(None, ".git/**/*.lock")
+        .expect("invalid rule");
+    builder.build()
 }
 
+/// Decides which paths are not transferred, both when walking the tree and for watcher events.
+///
+/// Like git, the ignore files of every directory apply to the entries below it, and rules of
+/// deeper directories take precedence. They are loaded on first use and cached per directory.
+///
+/// Rules are checked by descending precedence, and the first match decides:
+///
+/// 1. `--include` and `--exclude` globs, where includes win over excludes
+/// 2. `.syncdignore`, `.ignore` and `.gitignore`, in this order for each directory
+/// 3. `.git/info/exclude` of the root
+/// 4. global gitignore
+/// 5. hidden entries
 #[derive(Debug)]
 pub struct Ignore {
+    root: PathBuf,
+    /// globs given on the command line
+    overrides: Gitignore,
     /// global git ignore
     global_ignore: Gitignore,
-    local_ignore: Gitignore,
+    /// `.git/info/exclude` of the root
+    exclude: Mutex<Gitignore>,
+    /// ignore files of each directory
+    dirs: Mutex<HashMap<PathBuf, Arc<DirRules>>>,
+    ignore_dot: bool,
     ignore_hidden: bool,
 }
 
+/// Rules of the ignore files in a single directory, by descending precedence
+#[derive(Debug)]
+struct DirRules {
+    files: Vec<Gitignore>,
+}
+
+/// Rule which decided whether a path is skipped
+#[derive(Debug)]
+pub enum Explanation {
+    /// Matched a glob of `--include` or `--exclude`
+    Override(Glob),
+    /// Matched a rule of an ignore file, or the built-in rule if it has no file
+    Rule(Glob),
+    /// Hidden entry
+    Hidden,
+    /// No rule matched
+    NoMatch,
+    /// Inside of a skipped directory, which is not descended into
+    Parent(PathBuf, Box<Explanation>),
+}
+
+impl Explanation {
+    pub fn is_skipped(&self) -> bool {
+        match self {
+            Self::Override(glob) | Self::Rule(glob) => !glob.is_whitelist(),
+            Self::Hidden | Self::Parent(..) => true,
+            Self::NoMatch => false,
+        }
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Override(glob) if glob.is_whitelist() => {
+                let original = glob.original();
+                write!(f, "included by --include `{}`", &original[1..])
+            }
+            Self::Override(glob) => write!(f, "ignored by --exclude `{}`", glob.original()),
+            Self::Rule(glob) => {
+                let verb = if glob.is_whitelist() {
+                    "included"
+                } else {
+                    "ignored"
+                };
+                write!(f, "{} by `{}`", verb, glob.original())?;
+                match glob.from() {
+                    Some(from) => write!(f, " in {}", from.display()),
+                    None => write!(f, " (built-in rule)"),
+                }
+            }
+            Self::Hidden => write!(f, "ignored as hidden"),
+            Self::NoMatch => write!(f, "not ignored"),
+            Self::Parent(path, explanation) => {
+                write!(f, "inside of {}, which is {}", path.display(), explanation)
+            }
+        }
+    }
+}
+
+impl DirRules {
+    fn load(dir: &Path, ignore_dot: bool) -> Self {
+        let files = IGNORE_FILES
+            .iter()
+            .filter(|name| ignore_dot || **name != ".ignore")
+            .filter_map(|name| Self::load_file(&dir.join(name)))
+            .collect();
+        Self { files }
+    }
+
+    fn load_file(path: &Path) -> Option<Gitignore> {
+        if !path.is_file() {
+            return None;
+        }
+        let (gitignore, err) = Gitignore::new(path);
+        if let Some(e) = err {
+            debug!(path = %path.display(), error = %e, "ignore file failed");
+        }
+        Some(gitignore)
+    }
+}
+
 impl Ignore {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(root: PathBuf) -> IgnoreBuilder {
         IgnoreBuilder {
             root,
             ignore_dot: true,
             hidden: true,
+            excludes: Vec::new(),
+            includes: Vec::new(),
         }
     }
 
     pub fn should_skip_path(&self, path: &Path) -> bool {
-        Self::should_ignore_skip_path(&self.local_ignore, path)
-            .or_else(|| Self::should_ignore_skip_path(&self.global_ignore, path))
+        let is_dir = fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_dir());
+        self.should_skip(path, is_dir)
+    }
+
+    /// Same as `should_skip_path` for paths which do not necessarily exist.
+    pub fn should_skip(&self, path: &Path, is_dir: bool) -> bool {
+        self.explain(path, is_dir).is_skipped()
+    }
+
+    /// Returns the rule which decides whether the path is skipped.
+    ///
+    /// Entries inside of a skipped directory are skipped even if a rule includes them, since the
+    /// directory is not descended into when walking.
+    pub fn explain(&self, path: &Path, is_dir: bool) -> Explanation {
+        let ancestors: Vec<_> = path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| *dir != self.root && dir.starts_with(&self.root))
+            .collect();
+        for dir in ancestors.into_iter().rev() {
+            let explanation = self.explain_entry(dir, true);
+            if explanation.is_skipped() {
+                return Explanation::Parent(dir.to_path_buf(), Box::new(explanation));
+            }
+        }
+        self.explain_entry(path, is_dir)
+    }
+
+    /// Same as `explain`, assuming that the parents of the path are not skipped.
+    fn explain_entry(&self, path: &Path, is_dir: bool) -> Explanation {
+        if let Some(glob) = Self::match_ignore(&self.overrides, path, is_dir) {
+            return Explanation::Override(glob);
+        }
+        self.match_dirs(path, is_dir)
+            .or_else(|| {
+                let exclude = self.exclude.lock().expect("poisoned lock");
+                Self::match_ignore(&exclude, path, is_dir)
+            })
+            .or_else(|| Self::match_ignore(&self.global_ignore, path, is_dir))
+            .map(Explanation::Rule)
             .unwrap_or_else(|| {
-                if self.ignore_hidden {
-                    Self::is_hidden_or_any_parents(path)
+                if self.ignore_hidden && path != self.root && pathutil::is_hidden(path) {
+                    Explanation::Hidden
                 } else {
-                    false
+                    Explanation::NoMatch
                 }
             })
     }
 
-    fn is_hidden_or_any_parents(mut path: &Path) -> bool {
-        if pathutil::is_hidden(path) {
-            true
-        } else {
-            while let Some(parent) = path.parent() {
-                if pathutil::is_hidden(parent) {
-                    return true;
-                }
-                path = parent;
-            }
-            false
+    /// Returns whether the rules are read from the file at `path`.
+    pub fn is_ignore_file(&self, path: &Path) -> bool {
+        if path == exclude_path(&self.root) {
+            return true;
         }
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name,
+            None => return false,
+        };
+        path.starts_with(&self.root)
+            && IGNORE_FILES.contains(&name)
+            && (self.ignore_dot || name != ".ignore")
+    }
+
+    /// Reads the changed ignore file at `path` again on next use.
+    ///
+    /// Returns the directory whose entries are affected by the change.
+    pub fn reload(&self, path: &Path) -> Result<PathBuf, ignore::Error> {
+        if path == exclude_path(&self.root) {
+            *self.exclude.lock().expect("poisoned lock") = load_exclude(&self.root)?;
+            return Ok(self.root.clone());
+        }
+        let dir = path.parent().unwrap_or(&self.root);
+        self.dirs.lock().expect("poisoned lock").remove(dir);
+        Ok(dir.to_path_buf())
+    }
+
+    /// Forgets the cached rules of the removed directory at `path` and below.
+    pub fn forget(&self, path: &Path) {
+        let mut dirs = self.dirs.lock().expect("poisoned lock");
+        dirs.retain(|dir, _| !dir.starts_with(path));
+    }
+
+    /// Returns a walker of the directory at `path`, which skips the same entries as `should_skip`.
+    ///
+    /// The standard filters of the walker are disabled, so that no other rules apply.
+    pub fn walk_builder(self: &Arc<Self>, path: &Path) -> WalkBuilder {
+        self.builder(path, false)
+    }
+
+    /// Same as `walk_builder`, but also yields the ignore files even if they are skipped, so that
+    /// their changes are detected.
+    pub fn scan_builder(self: &Arc<Self>, path: &Path) -> WalkBuilder {
+        self.builder(path, true)
+    }
+
+    fn builder(self: &Arc<Self>, path: &Path, ignore_files: bool) -> WalkBuilder {
+        let ignore = self.clone();
+        let exclude_path = exclude_path(&self.root);
+        let mut builder = WalkBuilder::new(path);
+        builder.standard_filters(false).filter_entry(move |entry| {
+            let path = entry.path();
+            if ignore_files && (ignore.is_ignore_file(path) || exclude_path.starts_with(path)) {
+                return true;
+            }
+            let is_dir = entry
+                .file_type()
+                .is_some_and(|file_type| file_type.is_dir());
+            if entry.depth() == 0 {
+                return *path == ignore.root || !ignore.should_skip(path, is_dir);
+            }
+            // skipped directories are only descended into for the exclude file, e.g. `.git`
+            let parent = path.parent().unwrap_or(path);
+            if ignore_files && parent != ignore.root && exclude_path.starts_with(parent) {
+                return !ignore.should_skip(path, is_dir);
+            }
+            // the walk does not descend into skipped directories
+            !ignore.explain_entry(path, is_dir).is_skipped()
+        });
+        builder
+    }
+
+    /// Matches the path against the ignore files of its ancestors up to the root, deepest first.
+    fn match_dirs(&self, path: &Path, is_dir: bool) -> Option<Glob> {
+        path.ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(&self.root))
+            .find_map(|dir| {
+                let rules = self.dir_rules(dir);
+                rules
+                    .files
+                    .iter()
+                    .find_map(|gi| Self::match_ignore(gi, path, is_dir))
+            })
+    }
+
+    fn dir_rules(&self, dir: &Path) -> Arc<DirRules> {
+        let mut dirs = self.dirs.lock().expect("poisoned lock");
+        dirs.entry(dir.to_path_buf())
+            .or_insert_with(|| Arc::new(DirRules::load(dir, self.ignore_dot)))
+            .clone()
     }
 
-    fn should_ignore_skip_path(gi: &Gitignore, path: &Path) -> Option<bool> {
-        match gi.matched_path_or_any_parents(path, path.is_dir()) {
+    fn match_ignore(gi: &Gitignore, path: &Path, is_dir: bool) -> Option<Glob> {
+        match gi.matched_path_or_any_parents(path, is_dir) {
             ignore::Match::None => None,
-            ignore::Match::Ignore(_) => Some(true),
-            ignore::Match::Whitelist(_) => Some(false),
+            ignore::Match::Ignore(glob) | ignore::Match::Whitelist(glob) => Some(glob.clone()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn builder(dir: &TempDir) -> IgnoreBuilder {
+        let mut builder = Ignore::new(dir.path().to_path_buf());
+        builder.hidden(false);
+        builder
+    }
+
+    fn walk(ignore: &Arc<Ignore>, dir: &TempDir) -> Vec<PathBuf> {
+        let mut paths: Vec<_> = ignore
+            .walk_builder(dir.path())
+            .build()
+            .map(|entry| entry.unwrap().into_path())
+            .filter_map(|path| Some(path.strip_prefix(dir.path()).ok()?.to_path_buf()))
+            .filter(|path| !path.as_os_str().is_empty())
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn nested_ignore_files_take_precedence() {
+        let dir = TempDir::new();
+        dir.write(".gitignore", "*.log\nbuild/\n");
+        dir.write("sub/.gitignore", "!keep.log\n");
+        dir.write("a.log", "");
+        dir.write("build/out", "");
+        dir.write("sub/keep.log", "");
+        dir.write("sub/other.log", "");
+        let ignore = Arc::new(builder(&dir).build().unwrap());
+
+        let root = dir.path();
+        assert!(ignore.should_skip(&root.join("a.log"), false));
+        assert!(ignore.should_skip(&root.join("build"), true));
+        assert!(!ignore.should_skip(&root.join("build"), false));
+        assert!(!ignore.should_skip(&root.join("sub/keep.log"), false));
+        assert!(ignore.should_skip(&root.join("sub/other.log"), false));
+        assert_eq!(
+            walk(&ignore, &dir),
+            [Path::new("sub"), Path::new("sub/keep.log")]
+        );
+    }
+
+    #[test]
+    fn dot_ignore_files_can_be_disabled() {
+        let dir = TempDir::new();
+        dir.write(".ignore", "file\n");
+        let path = dir.write("file", "");
+
+        let ignore = builder(&dir).build().unwrap();
+        assert!(ignore.should_skip(&path, false));
+        let ignore = builder(&dir).no_ignore_dot(true).build().unwrap();
+        assert!(!ignore.should_skip(&path, false));
+    }
+
+    #[test]
+    fn git_exclude_applies_to_the_root() {
+        let dir = TempDir::new();
+        dir.write(".git/info/exclude", "*.tmp\n");
+        let ignore = builder(&dir).hidden(true).build().unwrap();
+
+        let root = dir.path();
+        assert!(ignore.should_skip(&root.join("sub/file.tmp"), false));
+        assert!(ignore.should_skip(&root.join(".git/refs/heads/main.lock"), false));
+        assert!(!ignore.should_skip(&root.join(".git/refs/heads/main"), false));
+    }
+
+    #[test]
+    fn explanations_name_the_rule() {
+        let dir = TempDir::new();
+        let gitignore = dir.write(".gitignore", "*.log\n");
+        let ignore = builder(&dir).build().unwrap();
+
+        let root = dir.path();
+        let explanation = ignore.explain(&root.join("a.log"), false);
+        assert!(explanation.is_skipped());
+        assert_eq!(
+            explanation.to_string(),
+            format!("ignored by `*.log` in {}", gitignore.display())
+        );
+        let explanation = ignore.explain(&root.join(".env"), false);
+        assert!(matches!(explanation, Explanation::Hidden));
+        assert_eq!(explanation.to_string(), "ignored as hidden");
+        let explanation = ignore.explain(&root.join("file"), false);
+        assert!(!explanation.is_skipped());
+        assert_eq!(explanation.to_string(), "not ignored");
+        assert!(!ignore.explain(root, true).is_skipped());
+
+        let ignore = builder(&dir).hidden(true).build().unwrap();
+        let explanation = ignore.explain(&root.join(".git/index.lock"), false);
+        assert_eq!(
+            explanation.to_string(),
+            "ignored by `.git/**/*.lock` (built-in rule)"
+        );
+    }
+
+    #[test]
+    fn entries_of_skipped_dirs_are_skipped() {
+        let dir = TempDir::new();
+        dir.write(".gitignore", "build/\n");
+        let ignore = builder(&dir).build().unwrap();
+
+        let root = dir.path();
+        let explanation = ignore.explain(&root.join("build/sub/file"), false);
+        assert!(explanation.is_skipped());
+        assert_eq!(
+            explanation.to_string(),
+            format!(
+                "inside of {}, which is ignored by `build/` in {}",
+                root.join("build").display(),
+                root.join(".gitignore").display()
+            )
+        );
+        let explanation = ignore.explain(&root.join(".hidden/file"), false);
+        assert_eq!(
+            explanation.to_string(),
+            format!(
+                "inside of {}, which is ignored as hidden",
+                root.join(".hidden").display()
+            )
+        );
+    }
+
+    #[test]
+    fn hidden_entries_can_be_included() {
+        let dir = TempDir::new();
+        let path = dir.write(".hidden/file", "");
+        let ignore = Arc::new(builder(&dir).hidden(true).build().unwrap());
+        assert!(!ignore.should_skip(&path, false));
+        assert_eq!(
+            walk(&ignore, &dir),
+            [Path::new(".hidden"), Path::new(".hidden/file")]
+        );
+    }
+
+    #[test]
+    fn includes_take_precedence_over_excludes_and_ignore_files() {
+        let dir = TempDir::new();
+        dir.write(".gitignore", "*.env\n");
+        let ignore = builder(&dir)
+            .exclude("*.log")
+            .exclude("target/")
+            .include("keep.log")
+            .include("local.env")
+            .build()
+            .unwrap();
+
+        let root = dir.path();
+        let explanation = ignore.explain(&root.join("a.log"), false);
+        assert!(explanation.is_skipped());
+        assert_eq!(explanation.to_string(), "ignored by --exclude `*.log`");
+        let explanation = ignore.explain(&root.join("sub/keep.log"), false);
+        assert!(!explanation.is_skipped());
+        assert_eq!(explanation.to_string(), "included by --include `keep.log`");
+        assert!(!ignore.should_skip(&root.join("local.env"), false));
+        assert!(ignore.should_skip(&root.join("other.env"), false));
+        assert!(ignore.should_skip(&root.join("target"), true));
+    }
+
+    #[test]
+    fn included_entries_of_skipped_dirs_are_skipped() {
+        let dir = TempDir::new();
+        dir.write("build/keep", "");
+        let ignore = Arc::new(
+            builder(&dir)
+                .exclude("build/")
+                .include("keep")
+                .build()
+                .unwrap(),
+        );
+
+        let path = dir.path().join("build/keep");
+        assert!(matches!(
+            ignore.explain(&path, false),
+            Explanation::Parent(..)
+        ));
+        assert!(ignore.should_skip(&path, false));
+        assert!(walk(&ignore, &dir).is_empty());
+    }
+
+    #[test]
+    fn syncdignore_takes_precedence_over_gitignore() {
+        let dir = TempDir::new();
+        dir.write(SYNCD_IGNORE, "!*.lock\n");
+        dir.write(".gitignore", "*.lock\n");
+        let ignore = builder(&dir).build().unwrap();
+        assert!(!ignore.should_skip(&dir.path().join("Cargo.lock"), false));
+    }
+
+    #[test]
+    fn ignore_files_are_recognized() {
+        let dir = TempDir::new();
+        let ignore = builder(&dir).no_ignore_dot(true).build().unwrap();
+
+        let root = dir.path();
+        assert!(ignore.is_ignore_file(&root.join("sub/.gitignore")));
+        assert!(ignore.is_ignore_file(&root.join(SYNCD_IGNORE)));
+        assert!(ignore.is_ignore_file(&root.join(".git/info/exclude")));
+        assert!(!ignore.is_ignore_file(&root.join(".ignore")));
+        assert!(!ignore.is_ignore_file(&root.join("gitignore")));
+        assert!(!ignore.is_ignore_file(Path::new("/elsewhere/.gitignore")));
+    }
+
+    #[test]
+    fn changed_ignore_files_are_reloaded() {
+        let dir = TempDir::new();
+        let gitignore = dir.write("sub/.gitignore", "a\n");
+        let exclude = dir.write(".git/info/exclude", "");
+        let ignore = builder(&dir).build().unwrap();
+
+        let root = dir.path();
+        let (a, b) = (root.join("sub/a"), root.join("sub/b"));
+        assert!(ignore.should_skip(&a, false));
+        assert!(!ignore.should_skip(&b, false));
+
+        // rules are cached until reloaded
+        std::fs::write(&gitignore, "b\n").unwrap();
+        assert!(ignore.should_skip(&a, false));
+        assert_eq!(ignore.reload(&gitignore).unwrap(), root.join("sub"));
+        assert!(!ignore.should_skip(&a, false));
+        assert!(ignore.should_skip(&b, false));
+
+        std::fs::write(&exclude, "c\n").unwrap();
+        assert_eq!(ignore.reload(&exclude).unwrap(), root);
+        assert!(ignore.should_skip(&root.join("c"), false));
+    }
+
+    #[test]
+    fn removed_dirs_are_forgotten() {
+        let dir = TempDir::new();
+        dir.write("sub/.gitignore", "a\n");
+        let ignore = builder(&dir).build().unwrap();
+
+        let path = dir.path().join("sub/a");
+        assert!(ignore.should_skip(&path, false));
+        std::fs::remove_dir_all(dir.path().join("sub")).unwrap();
+        ignore.forget(&dir.path().join("sub"));
+        assert!(!ignore.should_skip(&path, false));
+    }
+
+    #[test]
+    fn scans_include_skipped_ignore_files() {
+        let dir = TempDir::new();
+        dir.write(".gitignore", "");
+        dir.write(".git/info/exclude", "");
+        dir.write(".git/HEAD", "");
+        let ignore = Arc::new(builder(&dir).build().unwrap());
+
+        let mut paths: Vec<_> = ignore
+            .scan_builder(dir.path())
+            .build()
+            .map(|entry| entry.unwrap().into_path())
+            .collect();
+        paths.sort();
+        let root = dir.path();
+        assert_eq!(
+            paths,
+            [
+                root.to_path_buf(),
+                root.join(".git"),
+                root.join(".git/info"),
+                root.join(".git/info/exclude"),
+                root.join(".gitignore"),
+            ]
+        );
+    }
+}
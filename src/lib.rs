@@ -7,10 +7,20 @@ use memmap2::{Mmap, MmapOptions};
 use sha2::{Digest, Sha256};
 use tokio::io::{AsyncRead, AsyncWrite};
 
+pub mod atomic;
+pub mod auth;
+pub mod compress;
+pub mod confine;
+pub mod debounce;
 pub mod ignore;
 pub mod pathutil;
+pub mod poll;
 pub mod proto;
+pub mod signature;
 pub mod store;
+#[cfg(test)]
+mod testutil;
+pub mod tls;
 pub mod transport;
 pub mod write;
 
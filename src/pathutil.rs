@@ -4,6 +4,7 @@
 // Commit: 459a9c563706ef84b8710fab8727b770552ed29c
 
 use std::ffi::OsStr;
+use std::fs;
 use std::path::Path;
 
 /// Returns true if and only if this entry is considered to be hidden.
@@ -16,7 +17,7 @@ pub fn is_hidden(path: &Path) -> bool {
     use std::os::unix::ffi::OsStrExt;
 
     if let Some(name) = file_name(path) {
-        name.as_bytes().get(0) == Some(&b'.')
+        name.as_bytes().first() == Some(&b'.')
     } else {
         false
     }
@@ -92,3 +93,10 @@ pub fn file_name<P: AsRef<Path> + ?Sized>(path: &P) -> Option<&OsStr> {
 // pub fn file_name<'a, P: AsRef<Path> + ?Sized>(path: &'a P) -> Option<&'a OsStr> {
 //     path.as_ref().file_name()
 // }
+
+/// Returns true if the path itself is a symlink; the link is not followed.
+pub fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path)
+        .map(|metadata| metadata.file_type().is_symlink())
+        .unwrap_or(false)
+}
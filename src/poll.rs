@@ -0,0 +1,291 @@
+//! Polling scanner for file systems without change notifications, e.g. NFS, SSHFS or FUSE mounts.
+//!
+//! The tree is walked periodically and compared to the previous walk by type, size, modification
+//! time and permissions. Changes are reported as the same events the notify watchers produce.
+
+use std::collections::HashMap;
+use std::fs::{self, Permissions};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use notify::event::{CreateKind, DataChange, MetadataKind, ModifyKind, RemoveKind};
+use notify::{Event, EventKind};
+use tracing::debug;
+
+use crate::ignore::Ignore;
+
+/// Parses a polling interval command line argument in milliseconds, which has to be positive.
+pub fn parse_interval(value: &str) -> Result<u64, String> {
+    match value.parse() {
+        Ok(0) => Err("polling interval has to be positive".to_string()),
+        Ok(interval) => Ok(interval),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
+
+#[derive(Debug)]
+pub struct Scanner {
+    root: PathBuf,
+    ignore: Arc<Ignore>,
+    entries: HashMap<PathBuf, Entry>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Entry {
+    kind: Kind,
+    size: u64,
+    modified: Option<SystemTime>,
+    permissions: Permissions,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    File,
+    Dir,
+    Symlink,
+}
+
+impl Scanner {
+    /// Takes the initial snapshot of the tree, against which the first scan is compared.
+    pub fn new(root: PathBuf, ignore: Arc<Ignore>) -> Self {
+        let mut scanner = Self {
+            root,
+            ignore,
+            entries: HashMap::new(),
+        };
+        scanner.entries = scanner.walk().0;
+        scanner
+    }
+
+    /// Scans the tree every `interval` and passes the events to `f` until it returns false.
+    pub fn run(mut self, interval: Duration, mut f: impl FnMut(Event) -> bool) {
+        loop {
+            thread::sleep(interval);
+            for event in self.scan() {
+                if !f(event) {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Walks the tree and returns the changes since the last scan.
+    pub fn scan(&mut self) -> Vec<Event> {
+        let (mut entries, complete) = self.walk();
+        let mut events = Vec::new();
+
+        let mut removed: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(path, _)| !entries.contains_key(*path))
+            .map(|(path, entry)| (path.clone(), entry.kind))
+            .collect();
+        if complete {
+            // parents first, so that their children are covered by the recursive removal
+            removed.sort_by(|(a, _), (b, _)| a.cmp(b));
+            for (path, kind) in removed {
+                events.push(Event::new(EventKind::Remove(kind.remove_kind())).add_path(path));
+            }
+        } else {
+            // entries of unreadable directories are kept until they can be read again
+            for (path, _) in removed {
+                let entry = self.entries.remove(&path).expect("entry is known");
+                entries.insert(path, entry);
+            }
+        }
+
+        let mut changed: Vec<_> = entries
+            .iter()
+            .filter(|(path, entry)| self.entries.get(*path) != Some(entry))
+            .collect();
+        // parents are created before their children
+        changed.sort_by_key(|(path, _)| *path);
+        for (path, entry) in changed {
+            let kind = match self.entries.get(path) {
+                Some(old) if old.kind == entry.kind => match entry.kind {
+                    // changed modification times of directories only reflect their entries
+                    Kind::Dir if old.permissions == entry.permissions => continue,
+                    Kind::File | Kind::Symlink
+                        if old.size != entry.size || old.modified != entry.modified =>
+                    {
+                        EventKind::Modify(ModifyKind::Data(DataChange::Any))
+                    }
+                    _ => EventKind::Modify(ModifyKind::Metadata(MetadataKind::Permissions)),
+                },
+                Some(old) => {
+                    events.push(
+                        Event::new(EventKind::Remove(old.kind.remove_kind()))
+                            .add_path(path.clone()),
+                    );
+                    EventKind::Create(entry.kind.create_kind())
+                }
+                None => EventKind::Create(entry.kind.create_kind()),
+            };
+            events.push(Event::new(kind).add_path(path.clone()));
+        }
+
+        self.entries = entries;
+        events
+    }
+
+    /// Returns the entries of the tree and whether all directories could be read.
+    fn walk(&self) -> (HashMap<PathBuf, Entry>, bool) {
+        let mut entries = HashMap::new();
+        let mut complete = true;
+        for entry in self.ignore.scan_builder(&self.root).build() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    debug!(reason = %e, "failed to scan entry");
+                    complete = false;
+                    continue;
+                }
+            };
+            if entry.depth() == 0 {
+                continue;
+            }
+            let metadata = match fs::symlink_metadata(entry.path()) {
+                Ok(metadata) => metadata,
+                // removed during the walk
+                Err(_) => continue,
+            };
+            let kind = if metadata.file_type().is_symlink() {
+                Kind::Symlink
+            } else if metadata.is_dir() {
+                Kind::Dir
+            } else {
+                Kind::File
+            };
+            entries.insert(
+                entry.into_path(),
+                Entry {
+                    kind,
+                    size: metadata.len(),
+                    modified: metadata.modified().ok(),
+                    permissions: metadata.permissions(),
+                },
+            );
+        }
+        (entries, complete)
+    }
+}
+
+impl Kind {
+    fn create_kind(self) -> CreateKind {
+        match self {
+            Kind::Dir => CreateKind::Folder,
+            Kind::File | Kind::Symlink => CreateKind::File,
+        }
+    }
+
+    fn remove_kind(self) -> RemoveKind {
+        match self {
+            Kind::Dir => RemoveKind::Folder,
+            Kind::File | Kind::Symlink => RemoveKind::File,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn scanner(dir: &TempDir) -> Scanner {
+        let root = dir.path().to_path_buf();
+        let ignore = Ignore::new(root.clone()).hidden(false).build().unwrap();
+        Scanner::new(root, Arc::new(ignore))
+    }
+
+    fn event(kind: EventKind, path: PathBuf) -> Event {
+        Event::new(kind).add_path(path)
+    }
+
+    #[test]
+    fn new_entries_are_created_parents_first() {
+        let dir = TempDir::new();
+        let mut scanner = scanner(&dir);
+        assert!(scanner.scan().is_empty());
+
+        let file = dir.write("sub/file", "data");
+        dir.write(".hidden", "");
+        assert_eq!(
+            scanner.scan(),
+            [
+                event(
+                    EventKind::Create(CreateKind::Folder),
+                    dir.path().join("sub")
+                ),
+                event(EventKind::Create(CreateKind::File), file),
+            ]
+        );
+        assert!(scanner.scan().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn changed_entries_are_modified() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new();
+        let (data, mode) = (dir.write("data", "a"), dir.write("mode", ""));
+        let mut scanner = scanner(&dir);
+
+        fs::write(&data, "longer").unwrap();
+        fs::set_permissions(&mode, Permissions::from_mode(0o600)).unwrap();
+        // new entries only change the modification time of their directory
+        dir.write("sub/file", "");
+        let mut events = scanner.scan();
+        events.retain(|event| !event.paths[0].starts_with(dir.path().join("sub")));
+        assert_eq!(
+            events,
+            [
+                event(EventKind::Modify(ModifyKind::Data(DataChange::Any)), data),
+                event(
+                    EventKind::Modify(ModifyKind::Metadata(MetadataKind::Permissions)),
+                    mode
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn removed_entries_are_removed_parents_first() {
+        let dir = TempDir::new();
+        dir.write("sub/a", "");
+        dir.write("sub/b", "");
+        let replaced = dir.write("replaced", "");
+        let mut scanner = scanner(&dir);
+
+        fs::remove_dir_all(dir.path().join("sub")).unwrap();
+        fs::remove_file(&replaced).unwrap();
+        fs::create_dir(&replaced).unwrap();
+        assert_eq!(
+            scanner.scan(),
+            [
+                event(
+                    EventKind::Remove(RemoveKind::Folder),
+                    dir.path().join("sub")
+                ),
+                event(
+                    EventKind::Remove(RemoveKind::File),
+                    dir.path().join("sub/a")
+                ),
+                event(
+                    EventKind::Remove(RemoveKind::File),
+                    dir.path().join("sub/b")
+                ),
+                event(EventKind::Remove(RemoveKind::File), replaced.clone()),
+                event(EventKind::Create(CreateKind::Folder), replaced),
+            ]
+        );
+    }
+
+    #[test]
+    fn interval_arguments_have_to_be_positive() {
+        assert_eq!(parse_interval("500"), Ok(500));
+        assert!(parse_interval("0").is_err());
+    }
+}
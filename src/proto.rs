@@ -1,10 +1,122 @@
 use std::fmt::{self, Debug};
+use std::os::unix::fs::MetadataExt;
 use std::path::PathBuf;
 use std::{fs, io};
 
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+/// Version of the protocol spoken between `transfer` and `transfer-handler`.
+///
+/// Has to be increased with every incompatible change of the messages in this module.
+pub const PROTOCOL_VERSION: u32 = 14;
+
+/// First message of a session, sent by `transfer` before any transfer request.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Hello {
+    pub version: u32,
+    /// Capabilities the client wants to use in this session
+    pub capabilities: Capabilities,
+    /// Requested zstd level, only used with `Capabilities::ZSTD`
+    pub compression_level: i32,
+    /// Block size of signatures overriding the size chosen from the file size
+    pub block_size: Option<u32>,
+}
+
+/// Answer of `transfer-handler` to `Hello`.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum Welcome {
+    /// Session is accepted with the capabilities supported by both sides
+    Accepted {
+        version: u32,
+        capabilities: Capabilities,
+        /// zstd level supported by the handler closest to the requested one
+        compression_level: i32,
+        /// Nonce the client has to answer with `Authenticate` if the handler requires a
+        /// pre-shared key
+        challenge: Option<[u8; 32]>,
+    },
+    /// Session is rejected and the connection is closed afterwards
+    Rejected { version: u32, reason: String },
+}
+
+/// Answer of `transfer` to the challenge in `Welcome::Accepted`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Authenticate {
+    /// HMAC-SHA256 of the nonce keyed with the pre-shared key
+    pub answer: [u8; 32],
+}
+
+/// Result of the authentication; the connection is closed if it failed.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum AuthResult {
+    Accepted,
+    Rejected,
+}
+
+/// Set of optional protocol features.
+///
+/// Unknown capabilities of a newer peer are dropped when intersecting with the supported set.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    /// Files are compared and verified with sha256
+    pub const SHA256: Self = Self(1 << 0);
+    /// Symlinks are recreated as symlinks
+    pub const SYMLINKS: Self = Self(1 << 1);
+    /// Permissions and modification times are preserved
+    pub const METADATA: Self = Self(1 << 2);
+    /// Destination directories can be listed to remove extraneous files
+    pub const LIST: Self = Self(1 << 3);
+    /// Interrupted contents transfers are resumed from the bytes already received
+    pub const RESUME: Self = Self(1 << 4);
+    /// Chunks of data can be compressed with zstd
+    pub const ZSTD: Self = Self(1 << 5);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl Debug for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const NAMES: &[(Capabilities, &str)] = &[
+            (Capabilities::SHA256, "SHA256"),
+            (Capabilities::SYMLINKS, "SYMLINKS"),
+            (Capabilities::METADATA, "METADATA"),
+            (Capabilities::LIST, "LIST"),
+            (Capabilities::RESUME, "RESUME"),
+            (Capabilities::ZSTD, "ZSTD"),
+        ];
+        let mut set = f.debug_set();
+        let mut known = Self::empty();
+        for &(capability, name) in NAMES {
+            if self.contains(capability) {
+                set.entry(&format_args!("{}", name));
+                known = known.union(capability);
+            }
+        }
+        let unknown = self.0 & !known.0;
+        if unknown != 0 {
+            set.entry(&format_args!("{:#x}", unknown));
+        }
+        set.finish()
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct TransferRequest {
     pub id: Uuid,
@@ -12,6 +124,10 @@ pub struct TransferRequest {
     pub file_type: FileType,
     pub kind: TransferRequestKind,
     pub transfer: Option<Transfer>,
+    /// Target of the link for symlink requests
+    pub link_target: Option<PathBuf>,
+    /// Metadata to apply after the file was written
+    pub metadata: Option<Metadata>,
 }
 
 #[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
@@ -40,8 +156,62 @@ pub enum TransferRequestKind {
     Check,
     Delta,
     Contents,
-    Remove,
-    Rename { new_path: PathBuf },
+    /// Remove a file, symlink or directory; non-empty directories only if `recursive` is set
+    Remove {
+        recursive: bool,
+    },
+    Rename {
+        new_path: PathBuf,
+    },
+    /// Apply metadata without transferring any contents
+    Metadata,
+    /// List the entries of a directory, starting after the last entry of the previous page
+    List {
+        after: Option<PathBuf>,
+    },
+    /// Query the number of bytes of the contents already received for the transfer's shasum
+    Resume,
+    /// Fetch the next chunk of a signature which did not fit into the `Different` response
+    Signature {
+        offset: u64,
+    },
+}
+
+/// Metadata of a file or directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Metadata {
+    /// Permission bits
+    pub mode: u32,
+    pub mtime: Timestamp,
+    /// Access time is only transferred on request
+    pub atime: Option<Timestamp>,
+}
+
+impl Metadata {
+    pub fn from_fs(metadata: &fs::Metadata, with_atime: bool) -> Self {
+        Self {
+            mode: metadata.mode() & 0o7777,
+            mtime: Timestamp {
+                secs: metadata.mtime(),
+                nanos: metadata.mtime_nsec() as u32,
+            },
+            atime: if with_atime {
+                Some(Timestamp {
+                    secs: metadata.atime(),
+                    nanos: metadata.atime_nsec() as u32,
+                })
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// Seconds and nanoseconds since the unix epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Timestamp {
+    pub secs: i64,
+    pub nanos: u32,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -53,9 +223,71 @@ pub struct TransferResponse {
 #[derive(Debug, Deserialize, Serialize)]
 pub enum TransferResponseKind {
     Ok,
-    Different { signature: Vec<u8> },
+    /// Contents differ; the rest of the signature is fetched with `Signature` requests
+    Different {
+        /// First chunk of the serialized signature
+        signature: Vec<u8>,
+        /// Block size the signature was calculated with
+        block_size: u32,
+        /// Total size of the serialized signature
+        signature_size: u64,
+    },
+    /// Chunk of a signature
+    SignatureChunk {
+        data: Vec<u8>,
+    },
     NeedContents,
-    CantHandle { reason: String },
+    CantHandle {
+        reason: String,
+    },
+    /// Page of the entries of a directory, sorted by path
+    Listing {
+        entries: Vec<ListEntry>,
+        /// Further entries follow and are fetched with another `List` request
+        more: bool,
+    },
+    /// Number of bytes of the contents already received; the transfer continues from there
+    ResumeAt {
+        offset: u64,
+    },
+    /// A path of the request was refused by the handler
+    InvalidPath {
+        path: PathBuf,
+        violation: PathViolation,
+    },
+}
+
+/// Reason for refusing a path
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PathViolation {
+    /// Path is absolute
+    Absolute,
+    /// Path contains `..`
+    ParentDir,
+    /// Path goes through a symlink pointing outside of the root
+    SymlinkOutsideRoot,
+    /// Root itself cannot be removed or renamed
+    Root,
+}
+
+impl fmt::Display for PathViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            Self::Absolute => "path is absolute",
+            Self::ParentDir => "path contains '..'",
+            Self::SymlinkOutsideRoot => "path goes through a symlink pointing outside of the root",
+            Self::Root => "root cannot be removed or renamed",
+        };
+        f.write_str(msg)
+    }
+}
+
+/// Entry of a directory listing
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ListEntry {
+    /// Path relative to the root
+    pub path: PathBuf,
+    pub file_type: FileType,
 }
 
 impl From<io::Error> for TransferResponseKind {
@@ -77,6 +309,12 @@ pub struct Transfer {
     pub file_size: Option<usize>,
     /// Total size of the data
     pub data_size: Option<usize>,
+    /// Offset of the chunk in the data
+    pub offset: u64,
+    /// Data of the chunk is compressed with zstd
+    pub compressed: bool,
+    /// Last chunk of data whose total size is not known in advance
+    pub last: bool,
 }
 
 impl Debug for Transfer {
@@ -84,17 +322,48 @@ impl Debug for Transfer {
         f.debug_struct("Transfer")
             .field("kind", &self.kind)
             .field("data", &"[...]")
-            .field("shasum", &hex::encode(&self.shasum))
+            .field("shasum", &hex::encode(self.shasum))
             .field("file_size", &self.file_size)
             .field("data_size", &self.data_size)
+            .field("offset", &self.offset)
+            .field("compressed", &self.compressed)
+            .field("last", &self.last)
             .finish()
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
 pub enum TransferKind {
+    /// No data; in a contents request the file is created empty or truncated
     Empty,
     Contents,
     Delta,
     Signature,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_capabilities_are_dropped() {
+        let supported = Capabilities::SHA256.union(Capabilities::LIST);
+        let requested = Capabilities::LIST.union(Capabilities(1 << 63));
+        let negotiated = supported.intersection(requested);
+        assert_eq!(negotiated, Capabilities::LIST);
+        assert!(!negotiated.contains(Capabilities::SHA256));
+        assert!(negotiated.contains(Capabilities::empty()));
+    }
+
+    #[test]
+    fn capabilities_are_printed_by_name() {
+        let capabilities = Capabilities::SHA256
+            .union(Capabilities::ZSTD)
+            .union(Capabilities(1 << 40));
+        assert_eq!(
+            format!("{:?}", capabilities),
+            "{SHA256, ZSTD, 0x10000000000}"
+        );
+        assert_eq!(format!("{:?}", Capabilities::empty()), "{}");
+    }
+}
@@ -0,0 +1,58 @@
+//! Parameters of rsync signatures.
+
+use fast_rsync::SignatureOptions;
+
+/// Smallest block size, so that small files still produce useful deltas
+pub const MIN_BLOCK_SIZE: u32 = 512;
+/// Largest block size, so that huge files still get deltas with a reasonable granularity
+pub const MAX_BLOCK_SIZE: u32 = 128 * 1024;
+/// Bytes of the strong hash stored per block
+const CRYPTO_HASH_SIZE: u32 = 8;
+
+/// Chooses the block size for a file like rsync: the square root of the file size rounded to a
+/// multiple of 8.
+///
+/// This keeps the number of blocks and the size of the blocks balanced, so the signature grows
+/// with the square root of the file size instead of linearly.
+pub fn block_size(file_size: u64) -> u32 {
+    let sqrt = (file_size as f64).sqrt() as u64;
+    let block_size = (sqrt / 8 * 8).clamp(MIN_BLOCK_SIZE as u64, MAX_BLOCK_SIZE as u64);
+    block_size as u32
+}
+
+/// Parses a block size command line argument, which has to be positive.
+pub fn parse_block_size(value: &str) -> Result<u32, String> {
+    match value.parse() {
+        Ok(0) => Err("block size has to be positive".to_string()),
+        Ok(block_size) => Ok(block_size),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
+
+pub fn options(block_size: u32) -> SignatureOptions {
+    SignatureOptions {
+        block_size,
+        crypto_hash_size: CRYPTO_HASH_SIZE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_size_grows_with_square_root() {
+        assert_eq!(block_size(0), MIN_BLOCK_SIZE);
+        assert_eq!(block_size(1024 * 1024), 1024);
+        assert_eq!(block_size(1000 * 1000), 1000);
+        assert_eq!(block_size(1001 * 1001), 1000);
+        assert_eq!(block_size(u64::MAX), MAX_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn block_size_arguments_have_to_be_positive() {
+        assert_eq!(parse_block_size("4096"), Ok(4096));
+        assert!(parse_block_size("0").is_err());
+        assert!(parse_block_size("-1").is_err());
+    }
+}
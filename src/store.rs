@@ -1,72 +1,317 @@
 use std::collections::{hash_map, HashMap};
+use std::mem;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 use pin_project::pin_project;
 use sha2::{Digest, Sha256};
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tokio::{fs, io};
+use tracing::{debug, warn};
 
-/// Asynchronous store for open files and deltas.
+use crate::atomic::{self, Durability};
+
+/// Default of the memory limit for deltas
+pub const DEFAULT_DELTA_MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+
+/// Asynchronous store for open files, deltas and signatures.
+///
+/// Accumulates chunks of data in the store. Files data chunks are hashed with sha256 hasher and
+/// written to a temporary file next to the final path, which is only replaced when the file is
+/// finished.
+///
+/// The store outlives a single connection, so that a partially received file can be resumed by
+/// the next connection transferring the same contents. Entries without activity are removed with
+/// `expire`.
 ///
-/// Accumulates chunks of data in the store. Files data chunks are hashed with sha256 hasher.
-#[derive(Debug, Default)]
+/// Deltas are kept in memory until all deltas together exceed the memory limit; further chunks
+/// are spilled to temporary files.
+#[derive(Debug)]
 pub struct Store {
     files: HashMap<PathBuf, FileEntry>,
     deltas: HashMap<PathBuf, DeltaEntry>,
+    signatures: HashMap<PathBuf, SignatureEntry>,
+    /// bytes of deltas kept in memory
+    delta_memory: usize,
+    delta_memory_limit: usize,
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self::new(DEFAULT_DELTA_MEMORY_LIMIT)
+    }
 }
 
 impl Store {
+    pub fn new(delta_memory_limit: usize) -> Self {
+        Self {
+            files: Default::default(),
+            deltas: Default::default(),
+            signatures: Default::default(),
+            delta_memory: 0,
+            delta_memory_limit,
+        }
+    }
+
+    /// Returns the number of bytes of the file with the given shasum received so far.
+    pub fn file_offset(&self, path: &Path, shasum: [u8; 32]) -> u64 {
+        match self.files.get(path) {
+            Some(file_entry) if file_entry.shasum == shasum => file_entry.num_bytes,
+            _ => 0,
+        }
+    }
+
     /// Returns the number of total bytes written to the file so far.
+    ///
+    /// A chunk at offset 0 restarts the file; any other chunk has to continue exactly where the
+    /// previous one ended.
     pub async fn push_file_chunk(
         &mut self,
         path: PathBuf,
         shasum: [u8; 32],
+        offset: u64,
         data: &[u8],
     ) -> io::Result<u64> {
         let mut entry = self.files.entry(path.clone());
-        let mut file_entry = match entry {
+        let file_entry = match entry {
             hash_map::Entry::Occupied(ref mut entry) => entry.get_mut(),
             hash_map::Entry::Vacant(entry) => entry.insert(FileEntry::new(&path, shasum).await?),
         };
-        if file_entry.shasum != shasum {
-            // shasum changed => reset file entry
-            *file_entry = FileEntry::new(&path, shasum).await?;
+        if file_entry.shasum != shasum || (offset == 0 && file_entry.num_bytes != 0) {
+            // shasum changed or transfer restarted => reset file entry
+            let outdated = mem::replace(file_entry, FileEntry::new(&path, shasum).await?);
+            outdated.discard().await;
+        }
+        if offset != file_entry.num_bytes {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "chunk at offset {} does not continue the {} bytes received",
+                    offset, file_entry.num_bytes
+                ),
+            ));
         }
         file_entry.write_all(data).await?;
         file_entry.num_bytes += data.len() as u64;
+        file_entry.last_activity = Instant::now();
         Ok(file_entry.num_bytes)
     }
 
-    pub fn push_delta_chunk(&mut self, path: PathBuf, shasum: [u8; 32], data: &[u8]) -> &[u8] {
-        let delta_entry = self.deltas.entry(path).or_insert_with(|| DeltaEntry {
-            shasum,
-            delta: Vec::new(),
-        });
-        if shasum != delta_entry.shasum {
-            // shasum changed => reset delta
-            delta_entry.delta.clear();
-        }
-        delta_entry.delta.extend(data);
-        &delta_entry.delta
-    }
-
-    /// Returns the sha256 sum of the file if the file was in the store.
-    pub async fn remove_file(&mut self, path: PathBuf) -> io::Result<Option<[u8; 32]>> {
-        Ok(match self.files.entry(path) {
-            hash_map::Entry::Occupied(entry) => {
-                let mut file_entry = entry.remove();
-                file_entry.flush().await?;
-                let shasum = file_entry.hasher.finalize().into();
-                Some(shasum)
+    /// Returns the number of total bytes of the delta received so far.
+    ///
+    /// Chunks have to be pushed in order like for `push_file_chunk`.
+    pub async fn push_delta_chunk(
+        &mut self,
+        path: PathBuf,
+        shasum: [u8; 32],
+        offset: u64,
+        data: &[u8],
+    ) -> io::Result<u64> {
+        let delta_entry = self
+            .deltas
+            .entry(path.clone())
+            .or_insert_with(|| DeltaEntry::new(shasum));
+        if delta_entry.shasum != shasum || (offset == 0 && delta_entry.num_bytes != 0) {
+            // shasum changed or transfer restarted => reset delta
+            let outdated = mem::replace(delta_entry, DeltaEntry::new(shasum));
+            self.delta_memory -= outdated.memory();
+            outdated.discard().await;
+        }
+        if offset != delta_entry.num_bytes {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "delta chunk at offset {} does not continue the {} bytes received",
+                    offset, delta_entry.num_bytes
+                ),
+            ));
+        }
+
+        match &mut delta_entry.data {
+            DeltaData::Memory(delta)
+                if self.delta_memory + data.len() <= self.delta_memory_limit =>
+            {
+                delta.extend(data);
+                self.delta_memory += data.len();
             }
-            hash_map::Entry::Vacant(_) => None,
-        })
+            DeltaData::Memory(delta) => {
+                // memory limit reached => spill delta to disk
+                let temp_path = atomic::temp_path(&path);
+                debug!(path = %path.display(), temp_path = %temp_path.display(), "spilling delta");
+                let f = fs::File::create(&temp_path).await?;
+                let mut f = io::BufWriter::new(f);
+                if let Err(e) = async {
+                    f.write_all(delta).await?;
+                    f.write_all(data).await
+                }
+                .await
+                {
+                    let _ = fs::remove_file(&temp_path).await;
+                    return Err(e);
+                }
+                self.delta_memory -= delta.len();
+                delta_entry.data = DeltaData::File { f, temp_path };
+            }
+            DeltaData::File { f, .. } => f.write_all(data).await?,
+        }
+        delta_entry.num_bytes += data.len() as u64;
+        delta_entry.last_activity = Instant::now();
+        Ok(delta_entry.num_bytes)
+    }
+
+    /// Removes a completely written file from the store and moves it to its final path.
+    ///
+    /// The file is only moved if its data matches the expected sha256 sum; otherwise the
+    /// temporary file is removed.
+    ///
+    /// Returns the sha256 sum of the data if the file was in the store.
+    pub async fn finish_file(
+        &mut self,
+        path: &Path,
+        durability: Durability,
+    ) -> io::Result<Option<[u8; 32]>> {
+        let mut file_entry = match self.files.remove(path) {
+            Some(file_entry) => file_entry,
+            None => return Ok(None),
+        };
+        if let Err(e) = file_entry.flush().await {
+            file_entry.discard().await;
+            return Err(e);
+        }
+
+        let shasum: [u8; 32] = file_entry.hasher.finalize().into();
+        if shasum != file_entry.shasum {
+            let _ = fs::remove_file(&file_entry.temp_path).await;
+            return Ok(Some(shasum));
+        }
+
+        let f = file_entry.f.into_inner().into_std().await;
+        if let Err(e) = atomic::persist(&f, &file_entry.temp_path, path, durability) {
+            let _ = fs::remove_file(&file_entry.temp_path).await;
+            return Err(e);
+        }
+        Ok(Some(shasum))
     }
 
-    pub fn remove_delta(&mut self, path: &Path) {
-        self.deltas.remove(path);
+    /// Removes a completely received delta from the store.
+    pub async fn finish_delta(&mut self, path: &Path) -> io::Result<Option<Delta>> {
+        let delta_entry = match self.deltas.remove(path) {
+            Some(delta_entry) => delta_entry,
+            None => return Ok(None),
+        };
+        self.delta_memory -= delta_entry.memory();
+        match delta_entry.data {
+            DeltaData::Memory(delta) => Ok(Some(Delta::Memory(delta))),
+            DeltaData::File { mut f, temp_path } => {
+                if let Err(e) = f.flush().await {
+                    let _ = fs::remove_file(&temp_path).await;
+                    return Err(e);
+                }
+                Ok(Some(Delta::File(temp_path)))
+            }
+        }
+    }
+
+    /// Keeps a signature which is sent in chunks.
+    pub fn insert_signature(&mut self, path: PathBuf, signature: Vec<u8>) {
+        let signature_entry = SignatureEntry {
+            signature,
+            last_activity: Instant::now(),
+        };
+        self.signatures.insert(path, signature_entry);
+    }
+
+    /// Returns the chunk of at most `max_len` bytes of a signature starting at `offset`.
+    ///
+    /// The signature is removed after its last chunk was returned.
+    pub fn signature_chunk(&mut self, path: &Path, offset: u64, max_len: usize) -> Option<Vec<u8>> {
+        let signature_entry = self.signatures.get_mut(path)?;
+        let signature = &signature_entry.signature;
+        let start = (offset as usize).min(signature.len());
+        let end = start.saturating_add(max_len).min(signature.len());
+        let chunk = signature[start..end].to_vec();
+        if end == signature.len() {
+            self.signatures.remove(path);
+        } else {
+            signature_entry.last_activity = Instant::now();
+        }
+        Some(chunk)
+    }
+
+    /// Removes entries without activity for at least `max_age` and their temporary files.
+    ///
+    /// Returns the number of removed entries.
+    pub async fn expire(&mut self, max_age: Duration) -> usize {
+        let expired_files: Vec<_> = self
+            .files
+            .iter()
+            .filter(|(_, file_entry)| file_entry.last_activity.elapsed() >= max_age)
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in &expired_files {
+            if let Some(file_entry) = self.files.remove(path) {
+                file_entry.discard().await;
+            }
+        }
+
+        let expired_deltas: Vec<_> = self
+            .deltas
+            .iter()
+            .filter(|(_, delta_entry)| delta_entry.last_activity.elapsed() >= max_age)
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in &expired_deltas {
+            if let Some(delta_entry) = self.deltas.remove(path) {
+                self.delta_memory -= delta_entry.memory();
+                delta_entry.discard().await;
+            }
+        }
+
+        let num_signatures = self.signatures.len();
+        self.signatures
+            .retain(|_, signature_entry| signature_entry.last_activity.elapsed() < max_age);
+
+        expired_files.len() + expired_deltas.len() + num_signatures - self.signatures.len()
+    }
+
+    /// Releases data which can't be used anymore after a connection was closed.
+    ///
+    /// Deltas and signatures are discarded. Data of partial files is flushed to their temporary
+    /// files, and the entries are kept until they expire, so that the next connection can resume
+    /// them. Entries which fail to flush are discarded.
+    pub async fn release(&mut self) {
+        self.clear_deltas().await;
+        self.signatures.clear();
+        let mut failed = Vec::new();
+        for (path, file_entry) in self.files.iter_mut() {
+            if let Err(e) = file_entry.flush().await {
+                warn!(path = %path.display(), reason = %e, "dropping partial file");
+                failed.push(path.clone());
+            }
+        }
+        for path in &failed {
+            if let Some(file_entry) = self.files.remove(path) {
+                file_entry.discard().await;
+            }
+        }
+    }
+
+    /// Removes all entries and their temporary files.
+    pub async fn clear(&mut self) {
+        self.clear_deltas().await;
+        self.signatures.clear();
+        for (_, file_entry) in self.files.drain() {
+            file_entry.discard().await;
+        }
+    }
+
+    async fn clear_deltas(&mut self) {
+        for (_, delta_entry) in self.deltas.drain() {
+            delta_entry.discard().await;
+        }
+        self.delta_memory = 0;
     }
 }
 
@@ -75,21 +320,33 @@ impl Store {
 struct FileEntry {
     #[pin]
     f: io::BufWriter<fs::File>,
+    /// temporary file the data is written to
+    temp_path: PathBuf,
     /// expected sha256 sum of the final data
     shasum: [u8; 32],
     hasher: Sha256,
     num_bytes: u64,
+    last_activity: Instant,
 }
 
 impl FileEntry {
     pub async fn new(path: &Path, shasum: [u8; 32]) -> io::Result<Self> {
+        let temp_path = atomic::temp_path(path);
         Ok(Self {
-            f: io::BufWriter::new(fs::File::create(path).await?),
+            f: io::BufWriter::new(fs::File::create(&temp_path).await?),
+            temp_path,
             shasum,
             hasher: Default::default(),
             num_bytes: 0,
+            last_activity: Instant::now(),
         })
     }
+
+    /// Closes and removes the temporary file.
+    async fn discard(self) {
+        drop(self.f);
+        let _ = fs::remove_file(&self.temp_path).await;
+    }
 }
 
 impl AsyncWrite for FileEntry {
@@ -115,8 +372,208 @@ impl AsyncWrite for FileEntry {
     }
 }
 
+/// Completely received delta
+#[derive(Debug)]
+pub enum Delta {
+    Memory(Vec<u8>),
+    /// Delta spilled to a temporary file, which has to be removed after use
+    File(PathBuf),
+}
+
 #[derive(Debug)]
 struct DeltaEntry {
     shasum: [u8; 32],
-    delta: Vec<u8>,
+    data: DeltaData,
+    num_bytes: u64,
+    last_activity: Instant,
+}
+
+#[derive(Debug)]
+enum DeltaData {
+    Memory(Vec<u8>),
+    File {
+        f: io::BufWriter<fs::File>,
+        temp_path: PathBuf,
+    },
+}
+
+impl DeltaEntry {
+    fn new(shasum: [u8; 32]) -> Self {
+        Self {
+            shasum,
+            data: DeltaData::Memory(Vec::new()),
+            num_bytes: 0,
+            last_activity: Instant::now(),
+        }
+    }
+
+    /// Bytes of the delta kept in memory
+    fn memory(&self) -> usize {
+        match &self.data {
+            DeltaData::Memory(delta) => delta.len(),
+            DeltaData::File { .. } => 0,
+        }
+    }
+
+    /// Closes and removes the temporary file if the delta was spilled to disk.
+    async fn discard(self) {
+        if let DeltaData::File { f, temp_path } = self.data {
+            drop(f);
+            let _ = fs::remove_file(&temp_path).await;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SignatureEntry {
+    signature: Vec<u8>,
+    last_activity: Instant,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shasum_bytes;
+    use crate::testutil::TempDir;
+
+    #[tokio::test]
+    async fn files_are_resumed_after_release() {
+        let dir = TempDir::new();
+        let path = dir.path().join("file");
+        let shasum = shasum_bytes("hello world");
+        let mut store = Store::default();
+
+        assert_eq!(
+            store
+                .push_file_chunk(path.clone(), shasum, 0, b"hello")
+                .await
+                .unwrap(),
+            5
+        );
+        store.release().await;
+        assert_eq!(store.file_offset(&path, shasum), 5);
+        assert_eq!(store.file_offset(&path, shasum_bytes("other")), 0);
+
+        let gap = store
+            .push_file_chunk(path.clone(), shasum, 6, b"world")
+            .await;
+        assert_eq!(gap.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        store
+            .push_file_chunk(path.clone(), shasum, 5, b" world")
+            .await
+            .unwrap();
+        assert_eq!(
+            store.finish_file(&path, Durability::None).await.unwrap(),
+            Some(shasum)
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello world");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn files_restart_at_offset_zero() {
+        let dir = TempDir::new();
+        let path = dir.path().join("file");
+        let shasum = shasum_bytes("new");
+        let mut store = Store::default();
+
+        store
+            .push_file_chunk(path.clone(), shasum, 0, b"old")
+            .await
+            .unwrap();
+        store
+            .push_file_chunk(path.clone(), shasum, 0, b"new")
+            .await
+            .unwrap();
+        store.finish_file(&path, Durability::None).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn files_with_wrong_shasum_are_not_persisted() {
+        let dir = TempDir::new();
+        let path = dir.path().join("file");
+        let mut store = Store::default();
+
+        store
+            .push_file_chunk(path.clone(), shasum_bytes("expected"), 0, b"actual")
+            .await
+            .unwrap();
+        assert_eq!(
+            store.finish_file(&path, Durability::None).await.unwrap(),
+            Some(shasum_bytes("actual"))
+        );
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn inactive_entries_expire() {
+        let dir = TempDir::new();
+        let path = dir.path().join("file");
+        let mut store = Store::default();
+
+        store
+            .push_file_chunk(path.clone(), shasum_bytes("a"), 0, b"partial")
+            .await
+            .unwrap();
+        store
+            .push_delta_chunk(path.clone(), shasum_bytes("b"), 0, b"delta")
+            .await
+            .unwrap();
+        store.insert_signature(path.clone(), vec![0; 8]);
+        assert_eq!(store.expire(Duration::from_secs(60)).await, 0);
+        assert_eq!(store.expire(Duration::ZERO).await, 3);
+        assert_eq!(store.delta_memory, 0);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn signatures_are_returned_in_chunks() {
+        let path = PathBuf::from("file");
+        let mut store = Store::default();
+        store.insert_signature(path.clone(), (0..10).collect());
+
+        assert_eq!(store.signature_chunk(&path, 0, 4), Some(vec![0, 1, 2, 3]));
+        assert_eq!(store.signature_chunk(&path, 4, 4), Some(vec![4, 5, 6, 7]));
+        assert_eq!(store.signature_chunk(&path, 8, 4), Some(vec![8, 9]));
+        // removed after the last chunk
+        assert_eq!(store.signature_chunk(&path, 8, 4), None);
+    }
+
+    #[tokio::test]
+    async fn deltas_spill_above_memory_limit() {
+        let dir = TempDir::new();
+        let (small, large) = (dir.path().join("small"), dir.path().join("large"));
+        let shasum = shasum_bytes("");
+        let mut store = Store::new(8);
+
+        store
+            .push_delta_chunk(small.clone(), shasum, 0, b"abcd")
+            .await
+            .unwrap();
+        store
+            .push_delta_chunk(large.clone(), shasum, 0, b"0123")
+            .await
+            .unwrap();
+        assert_eq!(store.delta_memory, 8);
+        store
+            .push_delta_chunk(large.clone(), shasum, 4, b"4567")
+            .await
+            .unwrap();
+        assert_eq!(store.delta_memory, 4);
+
+        match store.finish_delta(&small).await.unwrap() {
+            Some(Delta::Memory(delta)) => assert_eq!(delta, b"abcd"),
+            delta => panic!("unexpected delta {:?}", delta),
+        }
+        match store.finish_delta(&large).await.unwrap() {
+            Some(Delta::File(temp_path)) => {
+                assert_eq!(std::fs::read(&temp_path).unwrap(), b"01234567")
+            }
+            delta => panic!("unexpected delta {:?}", delta),
+        }
+        assert_eq!(store.delta_memory, 0);
+        assert!(store.finish_delta(&large).await.unwrap().is_none());
+    }
 }
@@ -0,0 +1,185 @@
+//! TLS for the TCP transport based on rustls.
+//!
+//! Certificates and keys are read from PEM files. The client only trusts the given CA
+//! certificates, which can also be a self-signed server certificate.
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+use tokio_rustls::rustls::{
+    Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, ServerName,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// Reads all certificates of a PEM file.
+pub fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(invalid_data(format!(
+            "no certificates found in {}",
+            path.display()
+        )));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// Reads the first PKCS#8 or RSA private key of a PEM file.
+pub fn load_private_key(path: &Path) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) => {
+                return Ok(PrivateKey(key))
+            }
+            _ => (),
+        }
+    }
+    Err(invalid_data(format!(
+        "no private key found in {}",
+        path.display()
+    )))
+}
+
+fn load_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(&cert)
+            .map_err(|e| invalid_data(format!("invalid CA certificate: {}", e)))?;
+    }
+    Ok(roots)
+}
+
+/// Creates the acceptor of the handler.
+///
+/// If `client_ca` is given, clients have to authenticate with a certificate signed by it.
+pub fn acceptor(cert: &Path, key: &Path, client_ca: Option<&Path>) -> io::Result<TlsAcceptor> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match client_ca {
+        Some(client_ca) => builder
+            .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(load_roots(client_ca)?)),
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(load_certs(cert)?, load_private_key(key)?)
+        .map_err(|e| invalid_data(e.to_string()))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Creates the connector of the client, which only trusts the certificates in `ca`.
+///
+/// `client_auth` is the certificate and key to authenticate the client with.
+pub fn connector(ca: &Path, client_auth: Option<(&Path, &Path)>) -> io::Result<TlsConnector> {
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(load_roots(ca)?);
+    let config = match client_auth {
+        Some((cert, key)) => builder
+            .with_single_cert(load_certs(cert)?, load_private_key(key)?)
+            .map_err(|e| invalid_data(e.to_string()))?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Parses the name the server certificate is verified against.
+pub fn server_name(name: &str) -> io::Result<ServerName> {
+    ServerName::try_from(name).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid server name '{}'", name),
+        )
+    })
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name)
+    }
+
+    /// Connects to a loopback handler and exchanges a message in both directions.
+    async fn exchange(acceptor: TlsAcceptor, connector: TlsConnector) -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let mut stream = acceptor.accept(stream).await?;
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).await?;
+            stream.write_all(b"pong").await?;
+            stream.shutdown().await?;
+            Ok::<_, io::Error>(buf)
+        });
+
+        let stream = TcpStream::connect(addr).await?;
+        let client = async {
+            let mut stream = connector.connect(server_name("localhost")?, stream).await?;
+            stream.write_all(b"ping").await?;
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).await?;
+            Ok::<_, io::Error>(buf)
+        }
+        .await;
+        let server = server.await.unwrap();
+        assert_eq!(&client?, b"pong");
+        assert_eq!(&server?, b"ping");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn self_signed_certificate() {
+        let (cert, key) = (testdata("localhost.pem"), testdata("localhost.key"));
+        exchange(
+            acceptor(&cert, &key, None).unwrap(),
+            connector(&cert, None).unwrap(),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn client_certificate() {
+        let (cert, key) = (testdata("localhost.pem"), testdata("localhost.key"));
+        exchange(
+            acceptor(&cert, &key, Some(&cert)).unwrap(),
+            connector(&cert, Some((&cert, &key))).unwrap(),
+        )
+        .await
+        .unwrap();
+        let res = exchange(
+            acceptor(&cert, &key, Some(&cert)).unwrap(),
+            connector(&cert, None).unwrap(),
+        )
+        .await;
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn missing_keys_are_rejected() {
+        let cert = testdata("localhost.pem");
+        let e = load_private_key(&cert).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let e = load_certs(&testdata("localhost.key")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(server_name("not a name").is_err());
+    }
+}
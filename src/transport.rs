@@ -3,7 +3,7 @@ use std::io;
 use std::pin::Pin;
 use std::task::{ready, Context, Poll};
 
-use futures_util::{Sink, Stream};
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
 use pin_project::pin_project;
 use serde::{de::DeserializeOwned, Serialize};
 use tokio::io::{AsyncRead, AsyncWrite};
@@ -11,6 +11,32 @@ use tokio_serde::formats::SymmetricalBincode;
 use tokio_serde::SymmetricallyFramed;
 use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 
+/// Default maximum length of a single frame
+pub const DEFAULT_MAX_FRAME_LENGTH: usize = 8 * 1024 * 1024;
+
+/// Maximum size of the data chunks of files, deltas and signatures, and of the entries of a
+/// directory listing, in a single message
+pub const MAX_CHUNK_SIZE: usize = 1024 * 1024; // 1 MB
+
+/// Room for the remaining fields of a message carrying a chunk, e.g. paths and zstd overhead
+const MAX_MESSAGE_OVERHEAD: usize = 64 * 1024;
+
+/// Smallest maximum frame length which fits every message, given that data and listings are split
+/// into chunks of at most `MAX_CHUNK_SIZE`
+pub const MIN_MAX_FRAME_LENGTH: usize = MAX_CHUNK_SIZE + MAX_MESSAGE_OVERHEAD;
+
+/// Parses a maximum frame length command line argument.
+pub fn parse_max_frame_length(value: &str) -> Result<usize, String> {
+    let max_frame_length: usize = value.parse().map_err(|e| format!("{}", e))?;
+    if max_frame_length < MIN_MAX_FRAME_LENGTH {
+        return Err(format!(
+            "maximum frame length has to be at least {} bytes",
+            MIN_MAX_FRAME_LENGTH
+        ));
+    }
+    Ok(max_frame_length)
+}
+
 #[pin_project]
 pub struct BincodeTransport<Req, Resp, R, W>
 where
@@ -32,12 +58,19 @@ where
     R: AsyncRead,
     W: AsyncWrite,
 {
-    pub fn new(read: R, write: W) -> Self {
-        let length_delimited_read = FramedRead::new(read, LengthDelimitedCodec::new());
+    /// Creates a transport which refuses to send or receive frames longer than
+    /// `max_frame_length`.
+    pub fn new(read: R, write: W, max_frame_length: usize) -> Self {
+        Frames::new(read, write, max_frame_length).into_transport()
+    }
+
+    fn from_framed(
+        length_delimited_read: FramedRead<R, LengthDelimitedCodec>,
+        length_delimited_write: FramedWrite<W, LengthDelimitedCodec>,
+    ) -> Self {
         let stream =
             SymmetricallyFramed::new(length_delimited_read, SymmetricalBincode::<Req>::default());
 
-        let length_delimited_write = FramedWrite::new(write, LengthDelimitedCodec::new());
         let sink = tokio_serde::SymmetricallyFramed::new(
             length_delimited_write,
             SymmetricalBincode::<Resp>::default(),
@@ -47,6 +80,60 @@ where
     }
 }
 
+/// Length delimited frames which are not bound to a request and response type yet.
+///
+/// Used to exchange single messages before the actual transfer protocol starts, e.g. for the
+/// handshake. Already buffered data is kept when converting into a `BincodeTransport`.
+pub struct Frames<R, W> {
+    read: FramedRead<R, LengthDelimitedCodec>,
+    write: FramedWrite<W, LengthDelimitedCodec>,
+}
+
+impl<R: AsyncRead, W: AsyncWrite> Frames<R, W> {
+    pub fn new(read: R, write: W, max_frame_length: usize) -> Self {
+        let codec = || {
+            LengthDelimitedCodec::builder()
+                .max_frame_length(max_frame_length)
+                .new_codec()
+        };
+        Self {
+            read: FramedRead::new(read, codec()),
+            write: FramedWrite::new(write, codec()),
+        }
+    }
+
+    pub fn into_transport<Req, Resp>(self) -> BincodeTransport<Req, Resp, R, W>
+    where
+        Req: DeserializeOwned + Debug,
+        Resp: Serialize + Debug,
+    {
+        BincodeTransport::from_framed(self.read, self.write)
+    }
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> Frames<R, W> {
+    /// Sends a single message and flushes it.
+    pub async fn send<T: Serialize + Unpin>(&mut self, msg: T) -> io::Result<()> {
+        let mut sink =
+            SymmetricallyFramed::new(&mut self.write, SymmetricalBincode::<T>::default());
+        sink.send(msg).await
+    }
+
+    /// Receives a single message.
+    ///
+    /// Fails with `UnexpectedEof` if the connection was closed before a message arrived.
+    pub async fn recv<T: DeserializeOwned + Unpin>(&mut self) -> io::Result<T> {
+        let mut stream =
+            SymmetricallyFramed::new(&mut self.read, SymmetricalBincode::<T>::default());
+        stream.next().await.unwrap_or_else(|| {
+            Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed",
+            ))
+        })
+    }
+}
+
 // forward to Self::stream
 impl<Req, Resp, R, W> Stream for BincodeTransport<Req, Resp, R, W>
 where
@@ -89,3 +176,67 @@ where
         self.project().sink.poll_close(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, split, DuplexStream, ReadHalf, WriteHalf};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::proto::{TransferResponse, TransferResponseKind};
+
+    type DuplexFrames = Frames<ReadHalf<DuplexStream>, WriteHalf<DuplexStream>>;
+
+    fn frames(max_frame_length: usize) -> (DuplexFrames, DuplexFrames) {
+        let (a, b) = duplex(64 * 1024);
+        let (a_read, a_write) = split(a);
+        let (b_read, b_write) = split(b);
+        (
+            Frames::new(a_read, a_write, max_frame_length),
+            Frames::new(b_read, b_write, max_frame_length),
+        )
+    }
+
+    fn signature_chunk(len: usize) -> TransferResponse {
+        TransferResponse {
+            id: Uuid::new_v4(),
+            kind: TransferResponseKind::SignatureChunk {
+                data: vec![0xff; len],
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn largest_chunks_fit_into_smallest_frames() {
+        let (mut a, mut b) = frames(MIN_MAX_FRAME_LENGTH);
+        let (sent, received) = tokio::join!(
+            a.send(signature_chunk(MAX_CHUNK_SIZE)),
+            b.recv::<TransferResponse>()
+        );
+        sent.unwrap();
+        match received.unwrap().kind {
+            TransferResponseKind::SignatureChunk { data } => assert_eq!(data.len(), MAX_CHUNK_SIZE),
+            kind => panic!("unexpected response {:?}", kind),
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_above_limit_are_rejected() {
+        let (mut a, _b) = frames(1024);
+        assert!(a.send(signature_chunk(1024)).await.is_err());
+    }
+
+    #[test]
+    fn max_frame_length_arguments_fit_a_chunk() {
+        assert_eq!(
+            parse_max_frame_length("8388608"),
+            Ok(DEFAULT_MAX_FRAME_LENGTH)
+        );
+        assert_eq!(
+            parse_max_frame_length(&MIN_MAX_FRAME_LENGTH.to_string()),
+            Ok(MIN_MAX_FRAME_LENGTH)
+        );
+        assert!(parse_max_frame_length(&MAX_CHUNK_SIZE.to_string()).is_err());
+        assert!(parse_max_frame_length("large").is_err());
+    }
+}
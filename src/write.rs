@@ -1,6 +1,7 @@
 use std::io;
 
 use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
 
 pub struct WriterWithShasum<W: io::Write> {
     writer: W,
@@ -18,6 +19,11 @@ impl<W: io::Write> WriterWithShasum<W> {
     pub fn finalize(self) -> [u8; 32] {
         self.hasher.finalize().into()
     }
+
+    /// Returns the inner writer together with the sha256 sum of the written data.
+    pub fn into_inner(self) -> (W, [u8; 32]) {
+        (self.writer, self.hasher.finalize().into())
+    }
 }
 
 impl<W: io::Write> io::Write for WriterWithShasum<W> {
@@ -31,3 +37,51 @@ impl<W: io::Write> io::Write for WriterWithShasum<W> {
         self.writer.flush()
     }
 }
+
+/// Writer sending the written data in chunks of `chunk_size` over a channel.
+///
+/// Streams data produced by blocking code, e.g. a delta, to an async task. Each chunk is sent
+/// together with a flag marking the last chunk, which is sent by `finish`.
+pub struct ChunkWriter {
+    tx: mpsc::Sender<(Vec<u8>, bool)>,
+    chunk_size: usize,
+    buf: Vec<u8>,
+}
+
+impl ChunkWriter {
+    pub fn new(tx: mpsc::Sender<(Vec<u8>, bool)>, chunk_size: usize) -> Self {
+        Self {
+            tx,
+            chunk_size,
+            buf: Vec::with_capacity(chunk_size),
+        }
+    }
+
+    /// Sends the remaining data as the last chunk.
+    pub fn finish(mut self) -> io::Result<()> {
+        let chunk = std::mem::take(&mut self.buf);
+        self.send(chunk, true)
+    }
+
+    fn send(&self, chunk: Vec<u8>, last: bool) -> io::Result<()> {
+        self.tx
+            .blocking_send((chunk, last))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "receiver of chunks closed"))
+    }
+}
+
+impl io::Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.chunk_size - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        if self.buf.len() == self.chunk_size {
+            let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(self.chunk_size));
+            self.send(chunk, false)?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}